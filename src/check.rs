@@ -0,0 +1,294 @@
+//! Monitoring checks, in the style of Nagios plugins.
+//!
+//! `rback check` judges the status of the datasets (see `zfs::status`) and the health of the
+//! pools against thresholds, and reports with a single line, such as
+//!
+//! ```text
+//! RBACK WARNING - tank/home: newest snapshot 2d old | newest_age=172800s;86400;259200 ...
+//! ```
+//!
+//! and an exit code of 0 (OK), 1 (WARNING), 2 (CRITICAL) or 3 (UNKNOWN).
+
+use std::cmp;
+use std::fmt;
+use zfs::DataSetStatus;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok,
+    Warning,
+    // Unknown is worse than a warning, since it may be hiding something worse, but not as bad as
+    // something known to be critical.
+    Unknown,
+    Critical,
+}
+
+impl State {
+    /// The exit code of a plugin reporting this state.
+    pub fn code(&self) -> i32 {
+        match *self {
+            State::Ok => 0,
+            State::Warning => 1,
+            State::Critical => 2,
+            State::Unknown => 3,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A warning and critical threshold.  A value over the threshold is a problem.  Either may be
+/// left out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limit {
+    pub warn: Option<u64>,
+    pub crit: Option<u64>,
+}
+
+impl Limit {
+    fn judge(&self, value: u64) -> State {
+        if self.crit.map_or(false, |c| value > c) {
+            State::Critical
+        } else if self.warn.map_or(false, |w| value > w) {
+            State::Warning
+        } else {
+            State::Ok
+        }
+    }
+
+    /// The perfdata for a value checked against this limit.
+    fn perf(&self, label: &str, value: u64, unit: &str) -> String {
+        let show = |v: Option<u64>| v.map_or_else(String::new, |v| v.to_string());
+        format!("{}={}{};{};{}", label, value, unit, show(self.warn), show(self.crit))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Thresholds {
+    /// The age of the newest snapshot of each dataset, in seconds.
+    pub age: Limit,
+    /// How many snapshots each replication target is behind.
+    pub lag: Limit,
+    /// How many snapshots of each dataset have no sure data, either in the sure directory or in
+    /// the bksure store.
+    pub sure: Limit,
+}
+
+/// The result of a check.
+#[derive(Debug)]
+pub struct Check {
+    pub state: State,
+    problems: Vec<(State, String)>,
+    perf: Vec<String>,
+}
+
+impl Check {
+    pub fn new() -> Check {
+        Check {
+            state: State::Ok,
+            problems: vec![],
+            perf: vec![],
+        }
+    }
+
+    /// Note a problem.
+    pub fn problem(&mut self, state: State, message: String) {
+        self.state = cmp::max(self.state, state);
+        self.problems.push((state, message));
+    }
+
+    /// Judge the datasets against the thresholds.  Each dataset is only judged on the targets
+    /// that replicate it, which are all that `status` lists for it.
+    pub fn datasets(&mut self, th: &Thresholds, status: &[DataSetStatus]) {
+        let mut max_age = 0;
+        let mut max_lag = 0;
+        let mut max_missing = 0;
+
+        for st in status {
+            match st.age {
+                None => {
+                    if th.age.warn.is_some() || th.age.crit.is_some() {
+                        self.problem(State::Critical, format!("{}: no snapshots", st.dataset));
+                    }
+                }
+                Some(age) => {
+                    let age = cmp::max(age, 0) as u64;
+                    max_age = cmp::max(max_age, age);
+                    let state = th.age.judge(age);
+                    if state != State::Ok {
+                        self.problem(state, format!("{}: newest snapshot {} old", st.dataset,
+                                                    format_age(age)));
+                    }
+                }
+            }
+
+            for r in &st.replicas {
+                match (r.behind, &r.error) {
+                    (_, &Some(ref e)) => {
+                        self.problem(State::Unknown, format!("{}: {}", r.target, e));
+                    }
+                    (None, _) => {
+                        self.problem(State::Critical, format!("{}: {} not replicated", r.target,
+                                                              st.dataset));
+                    }
+                    (Some(lag), _) => {
+                        max_lag = cmp::max(max_lag, lag as u64);
+                        let state = th.lag.judge(lag as u64);
+                        if state != State::Ok {
+                            self.problem(state, format!("{}: {} {} behind", r.target,
+                                                        st.dataset, lag));
+                        }
+                    }
+                }
+            }
+
+            let missing = sure_missing(st) as u64;
+            max_missing = cmp::max(max_missing, missing);
+            let state = th.sure.judge(missing);
+            if state != State::Ok {
+                self.problem(state, format!("{}: {} snapshots without sure data", st.dataset,
+                                            missing));
+            }
+        }
+
+        self.perf.push(th.age.perf("newest_age", max_age, "s"));
+        self.perf.push(th.lag.perf("replica_lag", max_lag, ""));
+        self.perf.push(th.sure.perf("sure_missing", max_missing, ""));
+    }
+
+    /// Judge the health of a pool, as given by `zpool get health`.
+    pub fn pool(&mut self, pool: &str, health: &str) {
+        let state = match health {
+            "ONLINE" => State::Ok,
+            "DEGRADED" => State::Warning,
+            _ => State::Critical,
+        };
+        if state != State::Ok {
+            self.problem(state, format!("pool {} is {}", pool, health));
+        }
+    }
+
+    /// The line to print.  The worst problems come first, and there are only so many, since
+    /// the line is often shown in a narrow column.
+    pub fn line(&self) -> String {
+        let mut problems: Vec<_> = self.problems.iter().collect();
+        problems.sort_by(|a, b| b.0.cmp(&a.0));
+        let mut messages: Vec<_> = problems.iter().take(3).map(|p| &p.1[..]).collect();
+        let more;
+        if problems.len() > 3 {
+            more = format!("and {} more", problems.len() - 3);
+            messages.push(&more);
+        }
+
+        let summary = if messages.is_empty() {
+            "all datasets are current".to_owned()
+        } else {
+            messages.join("; ")
+        };
+        if self.perf.is_empty() {
+            format!("RBACK {} - {}", self.state, summary)
+        } else {
+            format!("RBACK {} - {} | {}", self.state, summary, self.perf.join(" "))
+        }
+    }
+}
+
+/// The number of snapshots with sure data in neither the sure directory nor the bksure store.
+fn sure_missing(st: &DataSetStatus) -> usize {
+    match st.bksure {
+        None => st.sure_missing.len(),
+        Some(_) => st.sure_missing.iter().filter(|s| st.bksure_missing.contains(s)).count(),
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    if secs < 2 * 60 * 60 {
+        format!("{}m", secs / 60)
+    } else if secs < 2 * 24 * 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}d", secs / (24 * 60 * 60))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use zfs::{DataSetStatus, ReplicaStatus};
+
+    fn status(dataset: &str, age: Option<i64>, behind: Option<usize>) -> DataSetStatus {
+        DataSetStatus {
+            dataset: dataset.to_owned(),
+            snapshots: 3,
+            newest: None,
+            created: None,
+            age: age,
+            snap_used: None,
+            sure: 3,
+            sure_missing: vec![],
+            bksure: None,
+            bksure_missing: vec![],
+            replicas: vec![ReplicaStatus {
+                target: "bk".to_owned(),
+                behind: behind,
+                error: None,
+            }],
+        }
+    }
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            age: Limit { warn: Some(3600), crit: Some(86400) },
+            lag: Limit { warn: Some(2), crit: Some(10) },
+            sure: Limit::default(),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mut check = Check::new();
+        check.datasets(&thresholds(), &[status("tank/a", Some(60), Some(0))]);
+        check.pool("tank", "ONLINE");
+        assert_eq!(check.state, State::Ok);
+        assert_eq!(check.line(), "RBACK OK - all datasets are current | \
+                                  newest_age=60s;3600;86400 replica_lag=0;2;10 sure_missing=0;;");
+
+        let mut check = Check::new();
+        check.datasets(&thresholds(), &[status("tank/a", Some(7200), Some(0)),
+                                        status("tank/b", Some(60), Some(3))]);
+        assert_eq!(check.state, State::Warning);
+        assert!(check.line().starts_with("RBACK WARNING - tank/a: newest snapshot 2h old; \
+                                          bk: tank/b 3 behind | newest_age=7200s;"));
+
+        let mut check = Check::new();
+        check.datasets(&thresholds(), &[status("tank/a", Some(60), Some(3)),
+                                        status("tank/b", None, None)]);
+        check.pool("tank", "DEGRADED");
+        assert_eq!(check.state, State::Critical);
+        assert!(check.line().starts_with("RBACK CRITICAL - tank/b: no snapshots; \
+                                          bk: tank/b not replicated; bk: tank/a 3 behind; \
+                                          and 1 more |"));
+        assert_eq!(check.state.code(), 2);
+    }
+
+    #[test]
+    fn test_excluded() {
+        // The target excludes tank/scratch, so it isn't listed there.
+        let mut scratch = status("tank/scratch", Some(60), None);
+        scratch.replicas.clear();
+        let mut check = Check::new();
+        check.datasets(&thresholds(), &[status("tank/a", Some(60), Some(0)), scratch]);
+        assert_eq!(check.state, State::Ok);
+        assert!(check.line().starts_with("RBACK OK - all datasets are current |"));
+    }
+}
@@ -2,6 +2,7 @@
 
 use hostname;
 use rustc_serialize::Decodable;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, Read};
 use std::path::Path;
@@ -30,6 +31,24 @@ error_chain! {
             description("Unknown host")
             display("Unknown host: {:?}", host)
         }
+        NoRoots(host: String) {
+            description("No roots configured")
+            display("Host {:?} has neither a base nor any roots", host)
+        }
+        NoPrefix(base: String) {
+            description("No snapshot prefix")
+            display("No snap_prefix given for root {:?}", base)
+        }
+        NoBase(host: String) {
+            description("Datasets without a base")
+            display("Host {:?} has datasets, but no base for them to be under (give them in \
+                     each root instead)", host)
+        }
+        SameLeaf(first: String, second: String) {
+            description("Roots with the same name")
+            display("Roots {:?} and {:?} would be cloned to the same place under a target",
+                    first, second)
+        }
     }
 }
 
@@ -39,8 +58,123 @@ error_chain! {
 #[derive(Clone, Debug, RustcDecodable)]
 pub struct Host {
     pub host: String,
+    /// A single dataset tree to manage.  More can be given with `roots`.
+    pub base: Option<String>,
+    pub snap_prefix: Option<String>,
+    /// How to name new snapshots.  See `zfs::naming` for the tokens.
+    pub snap_template: Option<String>,
+    pub roots: Option<Vec<Root>>,
+    pub targets: Option<Vec<Target>>,
+    pub retention: Option<Retention>,
+    /// Settings for datasets under `base`.  Unlike the other settings, these aren't given to the
+    /// `roots`, since the names are relative to the base.
+    pub datasets: Option<Vec<DataSetConfig>>,
+    /// The most snapshots to destroy in a single zfs command when pruning.
+    pub prune_batch: Option<usize>,
+    pub hooks: Option<Hooks>,
+    /// Where to keep the lock files that keep rback runs from interfering with each other.
+    pub lock_dir: Option<String>,
+    pub schedule: Option<Schedule>,
+    /// A file to write Prometheus metrics to after each run, for the node_exporter textfile
+    /// collector.  The name should end in ".prom".
+    pub metrics_file: Option<String>,
+}
+
+/// A dataset tree to manage, given as `[[name.roots]]` entries under the host.  Any settings not
+/// given here are taken from the host.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct Root {
     pub base: String,
-    pub snap_prefix: String,
+    pub snap_prefix: Option<String>,
+    pub snap_template: Option<String>,
+    pub retention: Option<Retention>,
+    pub datasets: Option<Vec<DataSetConfig>>,
+    pub prune_batch: Option<usize>,
+    pub hooks: Option<Hooks>,
+}
+
+/// Settings for cloning to a particular destination.  These are given as `[[name.targets]]`
+/// entries under the host.  Named targets can be replicated with `rback replicate`, and the
+/// settings also apply to `rback clone` to the same destination.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct Target {
+    pub name: Option<String>,
+    /// The destination, as it would be given to `rback clone`.  Each root is replicated to a
+    /// dataset under this named after the root.
+    pub dest: String,
+    /// Do an initial full send of datasets that aren't present on the destination.
+    pub fresh: Option<bool>,
+    /// Extra options to give ssh, for a remote destination.
+    pub ssh_options: Option<Vec<String>>,
+    /// Flags to give `zfs send`, instead of the default "-Le".
+    pub send_flags: Option<Vec<String>>,
+    /// Properties to override on the received datasets.
+    pub recv_set: Option<BTreeMap<String, String>>,
+    /// Properties to exclude from the received datasets.
+    pub recv_exclude: Option<Vec<String>>,
+    /// Datasets to replicate, relative to the root.  Datasets beneath these are included, and
+    /// those above them are created on a fresh destination, without their snapshots.  If not
+    /// given, everything is replicated.
+    pub include: Option<Vec<String>>,
+    /// Datasets not to replicate, relative to the root.
+    pub exclude: Option<Vec<String>>,
+}
+
+/// Settings that apply to a single dataset (and those below it), given as `[[name.datasets]]`
+/// entries under the host.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct DataSetConfig {
+    /// The name of the dataset, relative to the base.  An empty name refers to the base itself.
+    pub name: String,
+    pub retention: Option<Retention>,
+    pub hooks: Option<Hooks>,
+}
+
+/// Commands to run around taking a snapshot, such as to quiesce a database.  Each is run with
+/// `/bin/sh -c`.  See `zfs::hooks` for the environment they are given.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct Hooks {
+    /// Run before the snapshot is taken.
+    pub pre: Option<String>,
+    /// Run after the snapshot is attempted, whether or not it worked, so that it can undo
+    /// whatever `pre` did.
+    pub post: Option<String>,
+    /// Run when a pre hook or the snapshot itself fails.
+    pub failure: Option<String>,
+    /// How long, in seconds, each command may run before it is killed.  Defaults to 60.
+    pub timeout: Option<u64>,
+    /// What to do when the pre hook fails: "abort" (the default) doesn't take the snapshot, and
+    /// "continue" takes it anyway.
+    pub pre_failure: Option<String>,
+}
+
+/// How often `rback daemon` runs each job, as intervals such as "15m", "1h" or "1d".  Jobs
+/// without an interval aren't run.  Replication is to all of the targets.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct Schedule {
+    pub snap: Option<String>,
+    pub sure: Option<String>,
+    pub bksure: Option<String>,
+    pub prune: Option<String>,
+    pub replicate: Option<String>,
+    /// Where the daemon remembers when each job last ran, so that it can catch up on missed runs
+    /// after a restart.  Defaults to /var/lib/rback/daemon.json.
+    pub state_file: Option<String>,
+}
+
+/// How old snapshots are pruned.  The `policy` is either "popcount" (the default), which keeps
+/// snapshots based on the bits of the snapshot number, or "calendar", which keeps the newest
+/// snapshot in each of the given number of hours, days, weeks, months and years.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct Retention {
+    pub policy: Option<String>,
+    /// For "popcount", the number of prunable snapshots to always keep.
+    pub keep: Option<usize>,
+    pub hourly: Option<usize>,
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+    pub monthly: Option<usize>,
+    pub yearly: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -71,6 +205,174 @@ impl Host {
 
         Ok(ConfigFile(result))
     }
+
+    /// Find the target settings for the given clone destination, if the config has any.
+    pub fn target_for(&self, dest: &str) -> Option<&Target> {
+        self.all_targets().iter().find(|t| t.dest == dest)
+    }
+
+    /// Find the replication target with the given name.
+    pub fn target(&self, name: &str) -> Option<&Target> {
+        self.all_targets().iter().find(|t| t.name() == name)
+    }
+
+    /// All of the configured replication targets.
+    pub fn all_targets(&self) -> &[Target] {
+        match self.targets {
+            None => &[],
+            Some(ref targets) => targets,
+        }
+    }
+
+    /// Return all of the roots to manage, with the host's settings filled in where the roots
+    /// don't give their own.  The `base` of the host, if given, comes first, with the host's
+    /// `datasets`.
+    pub fn roots(&self) -> Result<Vec<Root>> {
+        let mut result = vec![];
+        match self.base {
+            Some(ref base) => {
+                result.push(Root {
+                    base: base.clone(),
+                    snap_prefix: None,
+                    snap_template: None,
+                    retention: None,
+                    datasets: self.datasets.clone(),
+                    prune_batch: None,
+                    hooks: None,
+                });
+            }
+            None if self.datasets.is_some() => {
+                return Err(ErrorKind::NoBase(self.host.clone()).into());
+            }
+            None => (),
+        }
+        if let Some(ref roots) = self.roots {
+            result.extend(roots.iter().cloned());
+        }
+        if result.is_empty() {
+            return Err(ErrorKind::NoRoots(self.host.clone()).into());
+        }
+
+        for root in &mut result {
+            if root.snap_prefix.is_none() {
+                root.snap_prefix = self.snap_prefix.clone();
+            }
+            if root.snap_prefix.is_none() {
+                return Err(ErrorKind::NoPrefix(root.base.clone()).into());
+            }
+            if root.snap_template.is_none() {
+                root.snap_template = self.snap_template.clone();
+            }
+            if root.retention.is_none() {
+                root.retention = self.retention.clone();
+            }
+            if root.prune_batch.is_none() {
+                root.prune_batch = self.prune_batch;
+            }
+            if root.hooks.is_none() {
+                root.hooks = self.hooks.clone();
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+impl Host {
+    /// A host that only manages the tree at `base`, with nothing else configured, for tests.
+    pub fn for_base(base: &str, snap_prefix: &str) -> Host {
+        Host {
+            host: "test-host".to_owned(),
+            base: Some(base.to_owned()),
+            snap_prefix: Some(snap_prefix.to_owned()),
+            snap_template: None,
+            roots: None,
+            targets: None,
+            retention: None,
+            datasets: None,
+            prune_batch: None,
+            hooks: None,
+            lock_dir: None,
+            schedule: None,
+            metrics_file: None,
+        }
+    }
+}
+
+impl Root {
+    /// The prefix for snapshot names.  This is always present in roots returned by
+    /// `Host::roots`.
+    pub fn snap_prefix(&self) -> &str {
+        self.snap_prefix.as_ref().map(|x| &x[..]).unwrap_or("")
+    }
+
+    /// The name of the pool that holds this root.
+    pub fn pool(&self) -> &str {
+        self.base.split('/').next().unwrap()
+    }
+
+    /// The last component of the base name, used to name this root under a clone destination.
+    pub fn leaf(&self) -> &str {
+        self.base.rsplit('/').next().unwrap()
+    }
+
+    /// The name this root is cloned to, under the given destination.
+    pub fn dest_under(&self, dest: &str) -> String {
+        format!("{}/{}", dest, self.leaf())
+    }
+
+    /// Look up a per-dataset setting for the dataset with the given name (relative to the base).
+    /// Settings are inherited, so this returns the value from the nearest ancestor that sets it.
+    pub fn dataset_setting<'a, T, F>(&'a self, sub: &str, get: F) -> Option<&'a T>
+        where F: Fn(&'a DataSetConfig) -> Option<&'a T>
+    {
+        let datasets = match self.datasets {
+            None => return None,
+            Some(ref datasets) => datasets,
+        };
+
+        let mut sub = sub;
+        loop {
+            for ds in datasets.iter().filter(|d| d.name == sub) {
+                if let Some(value) = get(ds) {
+                    return Some(value);
+                }
+            }
+            if sub.is_empty() {
+                return None;
+            }
+            sub = match sub.rfind('/') {
+                Some(pos) => &sub[..pos],
+                None => "",
+            };
+        }
+    }
+
+    /// Return the retention settings for the given dataset, preferring those of the dataset
+    /// over those for the whole root.
+    pub fn retention_for(&self, sub: &str) -> Option<&Retention> {
+        self.dataset_setting(sub, |d| d.retention.as_ref())
+            .or(self.retention.as_ref())
+    }
+}
+
+/// Check that the roots can be cloned under a single destination.  Each is cloned to a dataset
+/// named after its leaf, so these must differ.  Roots that are only managed locally may share a
+/// leaf, so this is only checked before cloning.
+pub fn check_leaves(roots: &[Root]) -> Result<()> {
+    for (i, root) in roots.iter().enumerate() {
+        if let Some(other) = roots[..i].iter().find(|r| r.leaf() == root.leaf()) {
+            return Err(ErrorKind::SameLeaf(other.base.clone(), root.base.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+impl Target {
+    /// The name of this target.  Unnamed targets go by their destination.
+    pub fn name(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.dest)
+    }
 }
 
 impl ConfigFile {
@@ -84,3 +386,57 @@ impl ConfigFile {
         return Err(ErrorKind::UnknownHost(host).into());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn root(base: &str) -> Root {
+        Root {
+            base: base.to_owned(),
+            snap_prefix: None,
+            snap_template: None,
+            retention: None,
+            datasets: None,
+            prune_batch: None,
+            hooks: None,
+        }
+    }
+
+    fn dataset(name: &str) -> DataSetConfig {
+        DataSetConfig {
+            name: name.to_owned(),
+            retention: None,
+            hooks: None,
+        }
+    }
+
+    #[test]
+    fn test_roots() {
+        let mut host = Host::for_base("tank/home", "h-");
+        host.datasets = Some(vec![dataset("db")]);
+        host.roots = Some(vec![root("otherpool/x")]);
+        let roots = host.roots().unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].datasets.as_ref().map(|d| d.len()), Some(1));
+        assert!(roots[1].datasets.is_none());
+        assert_eq!(roots[1].snap_prefix(), "h-");
+        assert_eq!(roots[1].dest_under("backup"), "backup/x");
+
+        host.base = None;
+        match host.roots().unwrap_err().kind() {
+            &ErrorKind::NoBase(ref name) => assert_eq!(name, "test-host"),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        host.datasets = None;
+        host.roots = Some(vec![root("tank/home"), root("backup/home")]);
+        let roots = host.roots().unwrap();
+        match check_leaves(&roots).unwrap_err().kind() {
+            &ErrorKind::SameLeaf(ref a, ref b) => {
+                assert_eq!((&a[..], &b[..]), ("tank/home", "backup/home"));
+            }
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+}
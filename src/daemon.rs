@@ -0,0 +1,337 @@
+//! Support for `rback daemon`, which runs the jobs on a schedule.
+//!
+//! The schedule gives an interval for each job.  When several jobs are due, they run one at a
+//! time, in the order snap, sure, bksure, prune, replicate, so that each sees the work of the ones
+//! before it.  When each job last ran is kept in a state file, so a job that came due while the
+//! daemon wasn't running (such as across a reboot) is run once, as soon as the daemon starts.
+//!
+//! SIGHUP asks the daemon to reload the config file, and SIGTERM (or SIGINT) asks it to stop.
+//! Long running work checks `stop_requested` between steps, so that it stops at a clean point,
+//! rather than in the middle of a send.  The `zfs send` and `zfs recv` are started in a process
+//! group of their own, so that a Ctrl-C at the terminal only reaches the daemon.  A service
+//! manager that signals every process of the service (systemd does, unless the unit has
+//! `KillMode=mixed`) will still cut a send short; the receive is done with `-s`, so the next
+//! replicate resumes it from the token left on the destination.
+
+use chrono::UTC;
+use config;
+use libc;
+use report::Summary;
+use rustc_serialize::json;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::Duration;
+
+error_chain! {
+    types {
+        Error, ErrorKind, ChainErr, Result;
+    }
+
+    links {
+    }
+
+    foreign_links {
+        io::Error, IoError;
+        json::DecoderError, JsonDecode;
+        json::EncoderError, JsonEncode;
+    }
+
+    errors {
+        BadInterval(text: String) {
+            description("Invalid schedule interval")
+            display("Invalid schedule interval: {:?}", text)
+        }
+        NoSchedule {
+            description("No schedule")
+            display("The config has no schedule for the daemon")
+        }
+    }
+}
+
+/// Where the state is kept, unless the config says otherwise.
+pub const DEFAULT_STATE_FILE: &'static str = "/var/lib/rback/daemon.json";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Job {
+    Snap,
+    Sure,
+    Bksure,
+    Prune,
+    Replicate,
+}
+
+impl Job {
+    /// All of the jobs, in the order they run.
+    pub fn all() -> [Job; 5] {
+        [Job::Snap, Job::Sure, Job::Bksure, Job::Prune, Job::Replicate]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Job::Snap => "snap",
+            Job::Sure => "sure",
+            Job::Bksure => "bksure",
+            Job::Prune => "prune",
+            Job::Replicate => "replicate",
+        }
+    }
+}
+
+impl fmt::Display for Job {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Parse an interval, such as "90s", "15m", "1h", "1d" or "1w", into seconds.
+pub fn parse_interval(text: &str) -> Result<i64> {
+    let bad = || ErrorKind::BadInterval(text.to_owned()).into();
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(bad());
+    }
+    let (num, unit) = text.split_at(text.len() - 1);
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(bad()),
+    };
+    match num.parse::<i64>() {
+        Ok(num) if num > 0 => Ok(num * scale),
+        _ => Err(bad()),
+    }
+}
+
+/// The jobs to run, and how often.
+#[derive(Debug)]
+pub struct Schedule {
+    jobs: Vec<(Job, i64)>,
+    pub state_file: PathBuf,
+}
+
+impl Schedule {
+    pub fn from_config(host: &config::Host) -> Result<Schedule> {
+        let cfg = match host.schedule {
+            None => return Err(ErrorKind::NoSchedule.into()),
+            Some(ref cfg) => cfg,
+        };
+
+        let mut jobs = vec![];
+        for &job in &Job::all() {
+            let interval = match job {
+                Job::Snap => &cfg.snap,
+                Job::Sure => &cfg.sure,
+                Job::Bksure => &cfg.bksure,
+                Job::Prune => &cfg.prune,
+                Job::Replicate => &cfg.replicate,
+            };
+            if let Some(ref interval) = *interval {
+                jobs.push((job, parse_interval(interval)?));
+            }
+        }
+
+        Ok(Schedule {
+            jobs: jobs,
+            state_file: PathBuf::from(cfg.state_file.as_ref()
+                                      .map_or(DEFAULT_STATE_FILE, |x| &x[..])),
+        })
+    }
+
+    /// The jobs that are due at `now`, in the order they should run.  Jobs that have never run
+    /// are due right away.
+    pub fn due(&self, state: &State, now: i64) -> Vec<Job> {
+        self.jobs.iter()
+            .filter(|&&(job, interval)| state.last_run(job).map_or(true, |t| t + interval <= now))
+            .map(|&(job, _)| job)
+            .collect()
+    }
+
+    /// When the next job comes due.
+    pub fn next_due(&self, state: &State) -> Option<i64> {
+        self.jobs.iter()
+            .map(|&(job, interval)| state.last_run(job).map_or(0, |t| t + interval))
+            .min()
+    }
+}
+
+/// What happened the last time a job ran.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct JobState {
+    pub job: String,
+    /// When it started, in seconds since the epoch.
+    pub last_run: i64,
+    pub seconds: f64,
+    /// As in the run report: "success", "partial" or "failure".
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// The state kept across runs of the daemon.
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    pub jobs: Vec<JobState>,
+}
+
+impl State {
+    /// Read the state file.  A missing file is the same as no job having run.
+    pub fn load(path: &Path) -> Result<State> {
+        let mut jobs = vec![];
+        match File::open(path) {
+            Ok(mut f) => {
+                let mut text = String::new();
+                f.read_to_string(&mut text)?;
+                jobs = json::decode(&text)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(State {
+            path: path.to_owned(),
+            jobs: jobs,
+        })
+    }
+
+    /// Write the state file.  A new file is written and renamed over the old one, so that a
+    /// crash doesn't leave a partial file.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            writeln!(f, "{}", json::as_pretty_json(&self.jobs))?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn last_run(&self, job: Job) -> Option<i64> {
+        self.get(job).map(|j| j.last_run)
+    }
+
+    pub fn get(&self, job: Job) -> Option<&JobState> {
+        self.jobs.iter().find(|j| j.job == job.name())
+    }
+
+    /// Record a run of the job, that started at `started`.
+    pub fn record(&mut self, job: Job, started: i64, summary: &Summary) {
+        self.jobs.retain(|j| j.job != job.name());
+        self.jobs.push(JobState {
+            job: job.name().to_owned(),
+            last_run: started,
+            seconds: summary.seconds,
+            status: summary.status.clone(),
+            error: summary.error.clone(),
+        });
+    }
+}
+
+/// The current time, in seconds since the epoch.
+pub fn now() -> i64 {
+    UTC::now().timestamp()
+}
+
+static STOP: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern fn on_signal(sig: libc::c_int) {
+    if sig == libc::SIGHUP {
+        RELOAD.store(true, Ordering::SeqCst);
+    } else {
+        STOP.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Catch SIGHUP, SIGTERM and SIGINT, instead of letting them kill us.
+pub fn catch_signals() {
+    let handler = on_signal as extern fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Has the daemon been asked to stop?  This is never true outside of the daemon.
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+/// Has the daemon been asked to reload the config since the last time this was asked?
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Sleep until the given time, or until a signal asks for something sooner.  With no time, sleep
+/// until a signal.
+pub fn sleep_until(time: Option<i64>) {
+    while time.map_or(true, |t| now() < t) {
+        if STOP.load(Ordering::SeqCst) || RELOAD.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::{Host, Schedule as ScheduleConfig};
+    use report::Report;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(parse_interval("90s").unwrap(), 90);
+        assert_eq!(parse_interval("15m").unwrap(), 900);
+        assert_eq!(parse_interval("1d").unwrap(), 86400);
+        assert!(parse_interval("").is_err());
+        assert!(parse_interval("10").is_err());
+        assert!(parse_interval("0h").is_err());
+        assert!(parse_interval("xh").is_err());
+    }
+
+    #[test]
+    fn test_due() {
+        let host = Host {
+            schedule: Some(ScheduleConfig {
+                snap: Some("1h".to_owned()),
+                sure: None,
+                bksure: None,
+                prune: Some("1d".to_owned()),
+                replicate: None,
+                state_file: None,
+            }),
+            ..Host::for_base("tank/home", "h-")
+        };
+        let schedule = Schedule::from_config(&host).unwrap();
+        let mut state = State {
+            path: PathBuf::from("/nonexistent"),
+            jobs: vec![],
+        };
+
+        // Nothing has run, so everything is due, in order.
+        let now = 1_000_000;
+        assert_eq!(schedule.due(&state, now), vec![Job::Snap, Job::Prune]);
+
+        let summary = Report::new().finish("test-host", "snap", false, None);
+        state.record(Job::Snap, now, &summary);
+        state.record(Job::Prune, now, &summary);
+        assert_eq!(schedule.due(&state, now + 10), vec![]);
+        assert_eq!(schedule.next_due(&state), Some(now + 3600));
+
+        // Long after, both are due, but only once.
+        assert_eq!(schedule.due(&state, now + 10 * 86400), vec![Job::Snap, Job::Prune]);
+        assert_eq!(state.jobs.len(), 2);
+    }
+}
@@ -3,18 +3,28 @@
 extern crate chrono;
 #[macro_use] extern crate error_chain;
 extern crate libc;
+extern crate openssl;
 extern crate regex;
 extern crate rsure;
 extern crate rustc_serialize;
 extern crate toml;
 
+pub mod check;
 pub mod config;
+pub mod daemon;
 pub mod hostname;
+pub mod lock;
+pub mod metrics;
+pub mod report;
 pub mod zfs;
 
-pub use zfs::{ZFS, ZfsPath};
+pub use zfs::{CloneOptions, ZFS, ZfsPath};
 
 pub struct RBack {
     pub host: config::Host,
     pub dry_run: bool,
+    /// Stop at the first dataset that fails, rather than carrying on with the rest.
+    pub fail_fast: bool,
+    /// What this run has done.
+    pub report: report::Report,
 }
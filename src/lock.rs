@@ -0,0 +1,164 @@
+//! Locking of pools.
+//!
+//! Commands that change a pool (or depend on its snapshots staying put) hold an advisory lock on
+//! it, so that, for instance, a prune can't destroy a snapshot that a clone is sending.  Each pool
+//! has a lock file, which is locked with `flock`, and holds the pid and start time of the holder.
+//! The kernel releases the lock when the holder exits, so a lock file still showing a holder
+//! that isn't locked was left behind by an rback that died.
+
+use chrono::{Local, TimeZone, UTC};
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+error_chain! {
+    types {
+        Error, ErrorKind, ChainErr, Result;
+    }
+
+    links {
+    }
+
+    foreign_links {
+        io::Error, IoError;
+    }
+
+    errors {
+        Held(pool: String, holder: String) {
+            description("Pool is locked")
+            display("Pool {:?} is locked, held by {}", pool, holder)
+        }
+    }
+}
+
+/// Where the lock files go, unless the config says otherwise.
+pub const DEFAULT_LOCK_DIR: &'static str = "/var/run/rback";
+
+/// A held lock on a pool.  The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct PoolLock {
+    file: File,
+}
+
+impl PoolLock {
+    /// Lock a single pool, with the lock file in `dir`.  If another process holds the lock,
+    /// either wait for it, or fail.
+    pub fn lock(dir: &Path, pool: &str, wait: bool) -> Result<PoolLock> {
+        fs::create_dir_all(dir)?;
+        let path = lock_path(dir, pool);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+
+        if !try_flock(&file, libc::LOCK_EX | libc::LOCK_NB)? {
+            let holder = read_holder(&mut file)?;
+            if !wait {
+                return Err(ErrorKind::Held(pool.to_owned(), holder).into());
+            }
+            println!("Waiting for lock on pool {:?}, held by {}", pool, holder);
+            try_flock(&file, libc::LOCK_EX)?;
+        }
+
+        let stale = read_holder(&mut file)?;
+        if !stale.is_empty() {
+            println!("Taking over stale lock on pool {:?}, left by {}", pool, stale);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{} {}", unsafe { libc::getpid() }, UTC::now().timestamp())?;
+        file.flush()?;
+
+        Ok(PoolLock { file: file })
+    }
+}
+
+impl Drop for PoolLock {
+    fn drop(&mut self) {
+        // Clear out our pid, so the next holder doesn't think we died holding the lock.  The
+        // lock itself goes away when the file is closed.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Lock every one of the given pools.  The pools are locked in order by name, so that two
+/// processes waiting for the same pools can't deadlock.
+pub fn lock_pools(dir: &Path, pools: &[&str], wait: bool) -> Result<Vec<PoolLock>> {
+    let mut pools = pools.to_vec();
+    pools.sort();
+    pools.dedup();
+
+    let mut result = vec![];
+    for pool in pools {
+        result.push(PoolLock::lock(dir, pool, wait)?);
+    }
+    Ok(result)
+}
+
+fn lock_path(dir: &Path, pool: &str) -> PathBuf {
+    dir.join(format!("{}.lock", pool))
+}
+
+/// Returns false if the lock would block.
+fn try_flock(file: &File, op: libc::c_int) -> Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), op) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(err.into())
+    }
+}
+
+/// Describe the holder recorded in the lock file, as "pid X since T".  Returns an empty string
+/// if the lock file has no holder.
+fn read_holder(file: &mut File) -> Result<String> {
+    let mut text = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut text)?;
+
+    let fields: Vec<_> = text.split_whitespace().collect();
+    Ok(match (fields.get(0), fields.get(1).and_then(|t| t.parse::<i64>().ok())) {
+        (None, _) => String::new(),
+        (Some(pid), Some(time)) => {
+            format!("pid {} since {}", pid, Local.timestamp(time, 0).format("%Y-%m-%d %H:%M:%S"))
+        }
+        (Some(pid), None) => format!("pid {}", pid),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libc;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_lock() {
+        let pid = unsafe { libc::getpid() };
+        let dir = env::temp_dir().join(format!("rback-lock-{}", pid));
+
+        {
+            let _held = lock_pools(&dir, &["tank", "arch", "tank"], false).unwrap();
+
+            // flock locks belong to the open file, so a second open conflicts, even within the
+            // same process.
+            let err = PoolLock::lock(&dir, "tank", false).unwrap_err();
+            match *err.kind() {
+                ErrorKind::Held(ref pool, ref holder) => {
+                    assert_eq!(pool, "tank");
+                    assert!(holder.starts_with(&format!("pid {} since ", pid)));
+                }
+                ref other => panic!("Unexpected lock error: {:?}", other),
+            }
+        }
+
+        // Dropped, so the lock is available again.
+        PoolLock::lock(&dir, "tank", false).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,28 +2,53 @@
 
 #[macro_use] extern crate clap;
 #[macro_use] extern crate error_chain;
+extern crate libc;
 extern crate rback;
+extern crate rustc_serialize;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rustc_serialize::json;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
+use std::process;
+use std::result;
+use std::sync::Arc;
 
-use rback::{zfs, ZFS, ZfsPath};
-use rback::config::Host;
+use rback::{daemon, lock, zfs, CloneOptions, ZFS, ZfsPath};
+use rback::check::{Check, Limit, State as CheckState, Thresholds};
+use rback::config::{self, Host, Root};
+use rback::daemon::{Job, Schedule, State};
+use rback::metrics::Metrics;
+use rback::report::{Report, Summary};
 
 use rback::RBack;
 
 error_chain! {
     links {
         zfs::Error, zfs::ErrorKind, Zfs;
+        config::Error, config::ErrorKind, Config;
+        daemon::Error, daemon::ErrorKind, Daemon;
+        lock::Error, lock::ErrorKind, Lock;
     }
 
     foreign_links {
     }
 
     errors {
+        UnknownTarget(name: String) {
+            description("Unknown replication target")
+            display("Unknown replication target: {:?}", name)
+        }
     }
 }
 
+const DEFAULT_CONFIG: &'static str = "backup.toml";
+
+/// The properties `rback props` shows, unless asked for others.
+const DEFAULT_PROPS: &'static str = "used,available,referenced,compressratio,mountpoint";
+
 fn main() {
     let matches = App::new("rback zfs backup management")
         .version(crate_version!())
@@ -37,98 +62,696 @@ fn main() {
              .short("n")
              .long("dry-run")
              .help("Don't make modifications to the filesystem"))
+        .arg(Arg::with_name("wait")
+             .long("wait")
+             .help("Wait for other rback runs to finish with the pools, instead of failing"))
+        .arg(Arg::with_name("fail-fast")
+             .long("fail-fast")
+             .help("Stop at the first dataset that fails, instead of carrying on with the rest"))
+        .arg(Arg::with_name("report")
+             .long("report")
+             .value_name("file")
+             .help("Write a JSON report of what was done to file")
+             .takes_value(true))
+        .arg(Arg::with_name("report-stdout")
+             .long("report-stdout")
+             .help("Write a JSON report of what was done to stdout, instead of messages"))
         .subcommand(SubCommand::with_name("snap")
                     .about("Take a snapshot"))
         .subcommand(SubCommand::with_name("sure")
-                    .about("Update sure info"))
+                    .about("Update sure info")
+                    .arg(jobs_arg()))
         .subcommand(SubCommand::with_name("bksure")
-                    .about("Update sure info to bksure store"))
+                    .about("Update sure info to bksure store")
+                    .arg(jobs_arg()))
         .subcommand(SubCommand::with_name("prune")
-                    .about("Prune old snapshots"))
+                    .about("Prune old snapshots")
+                    .arg(Arg::with_name("plan")
+                         .long("plan")
+                         .help("Show what would be pruned, and why"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .requires("plan")
+                         .help("Show the plan as JSON")))
+        .subcommand(SubCommand::with_name("sure-gc")
+                    .about("Remove sure data of snapshots that are gone"))
         .subcommand(SubCommand::with_name("props")
-                    .about("Debug: show props of volumes"))
+                    .about("Show properties of the datasets")
+                    .arg(Arg::with_name("props")
+                         .short("o")
+                         .value_name("prop,...")
+                         .takes_value(true)
+                         .help("The properties to show, or \"all\" (default used,available,\
+                                referenced,compressratio,mountpoint)"))
+                    .arg(Arg::with_name("snapshots")
+                         .long("snapshots")
+                         .help("Show the properties of the snapshots too"))
+                    .arg(Arg::with_name("source")
+                         .long("source")
+                         .value_name("source,...")
+                         .takes_value(true)
+                         .help("Only show properties from these sources: local, default, \
+                                inherited, received, temporary or none"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Show the properties as JSON")))
         .subcommand(SubCommand::with_name("clone")
                     .about("Clone a set of snapshots")
-                    .arg(Arg::with_name("src")
+                    .arg(Arg::with_name("fresh")
+                         .long("fresh")
+                         .help("Do a full send of datasets missing from dest"))
+                    .arg(Arg::with_name("paths")
+                         .value_name("[src] dest")
+                         .help("Clone src to dest, or every root to under dest")
+                         .required(true)
+                         .multiple(true)
+                         .min_values(1)
+                         .max_values(2)))
+        .subcommand(SubCommand::with_name("replicate")
+                    .about("Replicate to targets named in the config file")
+                    .arg(Arg::with_name("target")
+                         .help("The targets to replicate to (default all)")
+                         .multiple(true)))
+        .subcommand(SubCommand::with_name("status")
+                    .about("Show snapshot age, sure coverage and replication lag")
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Show the status as JSON")))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Compare the sure data of snapshots, looking for files changed in \
+                            place")
+                    .after_help("Files whose ctime didn't change keep the hash they were first \
+                                 given, so this can't find bit rot.  Use zpool scrub for that.")
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Show the changes as JSON")))
+        .subcommand(SubCommand::with_name("verify-replica")
+                    .about("Check the files of a replication target against the sure data")
+                    .arg(Arg::with_name("target")
+                         .help("The target to check")
                          .required(true))
-                    .arg(Arg::with_name("dest")
-                         .required(true)))
+                    .arg(Arg::with_name("snapshot")
+                         .long("snapshot")
+                         .takes_value(true)
+                         .help("Check this snapshot, instead of the newest with sure data"))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Show the result as JSON")))
+        .subcommand(SubCommand::with_name("check")
+                    .about("Check the backups, as a Nagios plugin")
+                    .arg(Arg::with_name("warn-age")
+                         .long("warn-age")
+                         .takes_value(true)
+                         .default_value("1d")
+                         .help("Warn when the newest snapshot is older than this"))
+                    .arg(Arg::with_name("crit-age")
+                         .long("crit-age")
+                         .takes_value(true)
+                         .default_value("3d")
+                         .help("Critical when the newest snapshot is older than this"))
+                    .arg(Arg::with_name("warn-lag")
+                         .long("warn-lag")
+                         .takes_value(true)
+                         .default_value("5")
+                         .help("Warn when a target is more than this many snapshots behind"))
+                    .arg(Arg::with_name("crit-lag")
+                         .long("crit-lag")
+                         .takes_value(true)
+                         .default_value("20")
+                         .help("Critical when a target is more than this many snapshots behind"))
+                    .arg(Arg::with_name("warn-sure")
+                         .long("warn-sure")
+                         .takes_value(true)
+                         .help("Warn when more than this many snapshots lack sure data"))
+                    .arg(Arg::with_name("crit-sure")
+                         .long("crit-sure")
+                         .takes_value(true)
+                         .help("Critical when more than this many snapshots lack sure data")))
+        .subcommand(SubCommand::with_name("daemon")
+                    .about("Run jobs on the schedule in the config file"))
         .get_matches();
 
-    let config = matches.value_of("config").unwrap_or("backup.toml");
-
-    let cfg = Host::load(&Path::new(config)).unwrap();
-    let host = cfg.lookup().unwrap();
+    let report = Report::new();
 
-    let back = RBack {
-        host: host.clone(),
-        dry_run: matches.is_present("dry-run"),
+    // With --report-stdout, the report is the only thing written to stdout.
+    let mut json_out = if matches.is_present("report-stdout") {
+        Some(take_stdout())
+    } else {
+        None
     };
 
-    match matches.subcommand_name() {
+    let command = match matches.subcommand_name() {
         None => {
             println!("{}", matches.usage());
             return;
         },
-        Some("snap") => do_snap(&back).unwrap(),
-        Some("sure") => do_sure(&back).unwrap(),
-        Some("bksure") => do_bksure(&back).unwrap(),
-        Some("prune") => do_prune(&back).unwrap(),
-        Some("props") => do_props(&back).unwrap(),
+        Some(command) => command,
+    };
+
+    let config = matches.value_of("config").unwrap_or(DEFAULT_CONFIG);
+    let dry_run = matches.is_present("dry-run");
+    let fail_fast = matches.is_present("fail-fast");
+
+    // Checks report in their own way, for the monitoring system.
+    if command == "check" {
+        let submatches = matches.subcommand_matches("check").unwrap();
+        let check = match do_check(config, submatches) {
+            Ok(check) => check,
+            Err(e) => {
+                let mut check = Check::new();
+                check.problem(CheckState::Unknown, e.to_string());
+                check
+            }
+        };
+        println!("{}", check.line());
+        process::exit(check.state.code());
+    }
+
+    let host = match load_host(config) {
+        Ok(host) => host,
+        Err(e) => {
+            let summary = report.finish("", command, dry_run, Some(e.to_string()));
+            finish(&matches, json_out.as_mut(), summary);
+        }
+    };
+
+    let back = RBack {
+        host: host,
+        dry_run: dry_run,
+        fail_fast: fail_fast,
+        report: report,
+    };
+    let result = run(&back, &matches);
+
+    let error = result.err().map(|e| e.to_string());
+    let summary = back.report.finish(&back.host.host, command, dry_run, error);
+    if needs_lock(&matches) {
+        write_metrics(&back, &summary);
+    }
+    finish(&matches, json_out.as_mut(), summary);
+}
+
+fn load_host(config: &str) -> Result<Host> {
+    let cfg = Host::load(&Path::new(config))?;
+    let host = cfg.lookup()?;
+    Ok(host.clone())
+}
+
+fn run(back: &RBack, matches: &ArgMatches) -> Result<()> {
+    let roots = back.host.roots()?;
+    let roots = &roots[..];
+
+    // Held until the command finishes.
+    let _locks = if needs_lock(matches) {
+        lock_roots(back, roots, matches.is_present("wait"))?
+    } else {
+        vec![]
+    };
+
+    match matches.subcommand_name() {
+        Some("snap") => do_snap(back, roots),
+        Some("sure") => do_sure(back, roots, jobs(matches.subcommand_matches("sure").unwrap())?),
+        Some("bksure") => {
+            do_bksure(back, roots, jobs(matches.subcommand_matches("bksure").unwrap())?)
+        }
+        Some("prune") => {
+            let submatches = matches.subcommand_matches("prune").unwrap();
+            if submatches.is_present("plan") {
+                do_prune_plan(back, roots, submatches.is_present("json"))
+            } else {
+                do_prune(back, roots)
+            }
+        }
+        Some("sure-gc") => do_sure_gc(back, roots),
+        Some("props") => do_props(back, roots, matches.subcommand_matches("props").unwrap()),
+        Some("status") => {
+            let submatches = matches.subcommand_matches("status").unwrap();
+            do_status(back, roots, submatches.is_present("json"))
+        }
+        Some("verify") => {
+            let submatches = matches.subcommand_matches("verify").unwrap();
+            do_verify(back, roots, submatches.is_present("json"))
+        }
+        Some("verify-replica") => {
+            let submatches = matches.subcommand_matches("verify-replica").unwrap();
+            do_verify_replica(back, roots, submatches.value_of("target").unwrap(),
+                              submatches.value_of("snapshot"), submatches.is_present("json"))
+        }
         Some("clone") => {
             let submatches = matches.subcommand_matches("clone").unwrap();
-            let src = submatches.value_of("src").unwrap();
-            let dest = submatches.value_of("dest").unwrap();
-            do_clone(&back, src, dest).unwrap();
+            let paths: Vec<_> = submatches.values_of("paths").unwrap().collect();
+            let fresh = submatches.is_present("fresh");
+            if paths.len() == 1 {
+                do_clone_roots(back, roots, paths[0], fresh)
+            } else {
+                do_clone(back, roots, paths[0], paths[1], fresh)
+            }
         }
+        Some("replicate") => {
+            let submatches = matches.subcommand_matches("replicate").unwrap();
+            let names: Vec<_> = submatches.values_of("target")
+                .map_or_else(Vec::new, |v| v.collect());
+            do_replicate(back, roots, &names)
+        }
+        Some("daemon") => do_daemon(back, matches.value_of("config").unwrap_or(DEFAULT_CONFIG)),
         Some(n) => panic!("Unexpected subcommand name: {}", n),
+        None => unreachable!(),
     }
+}
 
-    // println!("cfg: {:?}", host);
-    /*
-    let sudo = Sudo::new();
-    let zfs = ZFS::new(&sudo, &host.base, &host.snap_prefix);
-    zfs.take_snapshot().unwrap();
-    */
+/// Does the command need to lock the pools?  Only those that just look don't.  The daemon locks
+/// them for each job instead.
+fn needs_lock(matches: &ArgMatches) -> bool {
+    match matches.subcommand() {
+        ("props", _) | ("status", _) | ("verify", _) | ("verify-replica", _) |
+        ("daemon", _) => false,
+        ("prune", Some(submatches)) => !submatches.is_present("plan"),
+        _ => true,
+    }
+}
+
+/// Lock the pools of the given roots.  Nothing is locked for a dry run, as it changes nothing.
+fn lock_roots(back: &RBack, roots: &[Root], wait: bool) -> Result<Vec<lock::PoolLock>> {
+    if back.dry_run {
+        return Ok(vec![]);
+    }
+    let dir = back.host.lock_dir.as_ref().map_or(lock::DEFAULT_LOCK_DIR, |x| &x[..]);
+    let pools: Vec<_> = roots.iter().map(|r| r.pool()).collect();
+    Ok(lock::lock_pools(Path::new(dir), &pools, wait)?)
 }
 
-fn do_snap(back: &RBack) -> Result<()> {
-    let zfs = ZFS::new(back);
-    zfs.take_snapshot()?;
+/// Update the metrics file, if the config asks for one.  Problems are only reported, since the
+/// run itself is over.
+fn write_metrics(back: &RBack, summary: &Summary) {
+    let path = match back.host.metrics_file {
+        Some(ref path) if !back.dry_run => Path::new(path),
+        _ => return,
+    };
+
+    // Without the datasets, the metrics about them are left as they were.
+    let status = match dataset_status(back) {
+        Ok(status) => status,
+        Err(e) => {
+            println!("Unable to examine datasets for metrics: {}", e);
+            vec![]
+        }
+    };
+
+    let result = Metrics::load(path).and_then(|mut metrics| {
+        metrics.update(summary, &status);
+        metrics.write(path)
+    });
+    if let Err(e) = result {
+        println!("Unable to write metrics to {:?}: {}", path, e);
+    }
+}
+
+/// The status of the datasets of every root, without asking the replication targets.
+fn dataset_status(back: &RBack) -> Result<Vec<zfs::DataSetStatus>> {
+    let mut status = vec![];
+    for root in &back.host.roots()? {
+        let zfs = ZFS::new(back, root)?;
+        status.extend(zfs.status(false)?);
+    }
+    Ok(status)
+}
+
+/// Point stdout at stderr, so that everything printed while running goes there, returning the
+/// original stdout.
+fn take_stdout() -> File {
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            panic!("Unable to redirect stdout: {}", io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    }
+}
+
+/// Write out the report, and exit with its status.
+fn finish(matches: &ArgMatches, json_out: Option<&mut File>, summary: Summary) -> ! {
+    let _ = summary.show_failures(&mut io::stderr());
+    if let Some(ref error) = summary.error {
+        let _ = writeln!(io::stderr(), "Error: {}", error);
+    }
+    if let Some(out) = json_out {
+        if let Err(e) = summary.write(out) {
+            let _ = writeln!(io::stderr(), "Unable to write report: {}", e);
+        }
+    }
+    if let Some(name) = matches.value_of("report") {
+        if let Err(e) = File::create(name).and_then(|mut out| summary.write(&mut out)) {
+            let _ = writeln!(io::stderr(), "Unable to write report to {:?}: {}", name, e);
+        }
+    }
+    process::exit(summary.exit_code);
+}
+
+fn do_snap(back: &RBack, roots: &[Root]) -> Result<()> {
+    let mut zfss = vec![];
+    for root in roots {
+        zfss.push(ZFS::new(back, root)?);
+    }
+    let zfss: Vec<_> = zfss.iter().collect();
+    zfs::take_snapshots(&zfss)?;
     Ok(())
 }
 
-fn do_sure(back: &RBack) -> Result<()> {
-    let zfs = ZFS::new(back);
-    zfs.run_sure()?;
+fn jobs_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("jobs")
+        .short("j")
+        .long("jobs")
+        .value_name("N")
+        .takes_value(true)
+        .help("Hash up to N datasets at once")
+}
+
+/// The number of datasets to hash at once.
+fn jobs(matches: &ArgMatches) -> Result<usize> {
+    match matches.value_of("jobs") {
+        None => Ok(1),
+        Some(text) => match text.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Invalid number of jobs: {:?}", text).into()),
+        },
+    }
+}
+
+fn do_sure(back: &RBack, roots: &[Root], jobs: usize) -> Result<()> {
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        zfs.run_sure(jobs)?;
+    }
+    Ok(())
+}
+
+fn do_bksure(back: &RBack, roots: &[Root], jobs: usize) -> Result<()> {
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        zfs.run_bksure(jobs)?;
+    }
+    Ok(())
+}
+
+/// Prune the snapshots, and then the sure data of the ones destroyed.
+fn do_prune(back: &RBack, roots: &[Root]) -> Result<()> {
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        zfs.prune_snaps()?;
+        zfs.sure_gc()?;
+    }
     Ok(())
 }
 
-fn do_bksure(back: &RBack) -> Result<()> {
-    let zfs = ZFS::new(back);
-    zfs.run_bksure()?;
+fn do_sure_gc(back: &RBack, roots: &[Root]) -> Result<()> {
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        zfs.sure_gc()?;
+    }
     Ok(())
 }
 
-fn do_prune(back: &RBack) -> Result<()> {
-    let zfs = ZFS::new(back);
-    zfs.prune_snaps()?;
+fn do_prune_plan(back: &RBack, roots: &[Root], json: bool) -> Result<()> {
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        zfs.prune_plan(json)?;
+    }
     Ok(())
 }
 
-fn do_clone(back: &RBack, src: &str, dest: &str) -> Result<()> {
-    let zfs = ZFS::new(back);
+fn clone_options(back: &RBack, dest: &str, fresh: bool) -> CloneOptions {
+    let mut opts = match back.host.target_for(dest) {
+        Some(target) => CloneOptions::from_target(target),
+        None => CloneOptions::default(),
+    };
+    opts.fresh |= fresh;
+    opts
+}
+
+/// Parse a clone destination `path`, using the ssh options of the target for `dest`, if there is
+/// one.
+fn dest_path(back: &RBack, dest: &str, path: &str) -> Arc<ZfsPath> {
+    match back.host.target_for(dest).and_then(|t| t.ssh_options.as_ref()) {
+        Some(ssh) => ZfsPath::parse_ssh(path, ssh),
+        None => ZfsPath::parse(path),
+    }
+}
+
+fn do_clone(back: &RBack, roots: &[Root], src: &str, dest: &str, fresh: bool) -> Result<()> {
+    // Use the settings of the root being cloned, if there is one.
+    let root = roots.iter()
+        .find(|r| src == r.base || src.starts_with(&format!("{}/", r.base)))
+        .unwrap_or(&roots[0]);
+    let zfs = ZFS::new(back, root)?;
     println!("src: {}, dest: {}", src, dest);
 
+    let opts = clone_options(back, dest, fresh);
     let src = ZfsPath::parse(src);
-    let dest = ZfsPath::parse(dest);
-    zfs.clone_snaps(src, dest)?;
+    let dest = dest_path(back, dest, dest);
+    zfs.clone_snaps(src, dest, &opts)?;
+    Ok(())
+}
+
+/// Clone every root to a dataset named after the last component of its base, under `dest`.
+fn do_clone_roots(back: &RBack, roots: &[Root], dest: &str, fresh: bool) -> Result<()> {
+    config::check_leaves(roots)?;
+    let opts = clone_options(back, dest, fresh);
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        let rdest = root.dest_under(dest);
+        println!("src: {}, dest: {}", root.base, rdest);
+
+        zfs.clone_snaps(zfs::local_path(&root.base), dest_path(back, dest, &rdest), &opts)?;
+    }
     Ok(())
 }
 
-fn do_props(back: &RBack) -> Result<()> {
-    let zfs = ZFS::new(back);
-    zfs.show_props()?;
+fn do_replicate(back: &RBack, roots: &[Root], names: &[&str]) -> Result<()> {
+    config::check_leaves(roots)?;
+    let mut targets = vec![];
+    if names.is_empty() {
+        targets.extend(back.host.all_targets());
+    } else {
+        for name in names {
+            match back.host.target(name) {
+                Some(target) => targets.push(target),
+                None => return Err(ErrorKind::UnknownTarget(name.to_string()).into()),
+            }
+        }
+    }
+
+    for target in targets {
+        for root in roots {
+            let zfs = ZFS::new(back, root)?;
+            zfs.replicate(target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the jobs in the config's schedule until asked to stop.
+fn do_daemon(back: &RBack, config: &str) -> Result<()> {
+    daemon::catch_signals();
+
+    let mut host = back.host.clone();
+    let mut schedule = Schedule::from_config(&host)?;
+    let mut state = State::load(&schedule.state_file)?;
+    println!("Daemon started, state in {:?}", schedule.state_file);
+
+    while !daemon::stop_requested() {
+        if daemon::reload_requested() {
+            println!("Reloading {:?}", config);
+            let loaded = load_host(config).and_then(|h| {
+                let s = Schedule::from_config(&h)?;
+                let st = State::load(&s.state_file)?;
+                Ok((h, s, st))
+            });
+            match loaded {
+                Ok((h, s, st)) => {
+                    host = h;
+                    schedule = s;
+                    state = st;
+                }
+                Err(e) => println!("Error reloading config, keeping the old one: {}", e),
+            }
+        }
+
+        for job in schedule.due(&state, daemon::now()) {
+            if daemon::stop_requested() {
+                break;
+            }
+            let started = daemon::now();
+            let summary = run_job(&host, back.dry_run, back.fail_fast, job);
+            state.record(job, started, &summary);
+            state.save()?;
+        }
+
+        daemon::sleep_until(schedule.next_due(&state));
+    }
+    println!("Daemon stopping");
+    Ok(())
+}
+
+/// Run a single job for the daemon.  Each job gets its own report.
+fn run_job(host: &Host, dry_run: bool, fail_fast: bool, job: Job) -> Summary {
+    println!("Job {} starting", job);
+    let back = RBack {
+        host: host.clone(),
+        dry_run: dry_run,
+        fail_fast: fail_fast,
+        report: Report::new(),
+    };
+    let result = host.roots().map_err(|e| e.into()).and_then(|roots| {
+        // Wait for anything else using the pools, rather than skipping the job.
+        let _locks = lock_roots(&back, &roots, true)?;
+        match job {
+            Job::Snap => do_snap(&back, &roots),
+            Job::Sure => do_sure(&back, &roots, 1),
+            Job::Bksure => do_bksure(&back, &roots, 1),
+            Job::Prune => do_prune(&back, &roots),
+            Job::Replicate => do_replicate(&back, &roots, &[]),
+        }
+    });
+    if let Err(ref e) = result {
+        println!("Job {} error: {}", job, e);
+    }
+
+    let error = result.err().map(|e| e.to_string());
+    let summary = back.report.finish(&host.host, job.name(), dry_run, error);
+    let _ = summary.show_failures(&mut io::stdout());
+    println!("Job {} finished: {}", job, summary.status);
+    write_metrics(&back, &summary);
+    summary
+}
+
+fn do_status(back: &RBack, roots: &[Root], json: bool) -> Result<()> {
+    let mut status = vec![];
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        status.extend(zfs.status(true)?);
+    }
+    if json {
+        println!("{}", json::as_pretty_json(&status));
+    } else {
+        zfs::show_status(&status);
+    }
+    Ok(())
+}
+
+/// Compare the sure data of the snapshots.  Suspect files are recorded as errors in the report,
+/// so they give a failing exit status.
+fn do_verify(back: &RBack, roots: &[Root], json: bool) -> Result<()> {
+    let mut changes = vec![];
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        changes.extend(zfs.verify()?);
+    }
+    if json {
+        println!("{}", json::as_pretty_json(&changes));
+    } else {
+        zfs::show_verify(&changes);
+    }
+    Ok(())
+}
+
+/// Check the files on a replication target against the sure data.  Files that don't match are
+/// recorded as errors in the report.
+fn do_verify_replica(back: &RBack, roots: &[Root], name: &str, snap: Option<&str>,
+                     json: bool) -> Result<()> {
+    let target = match back.host.target(name) {
+        Some(target) => target,
+        None => return Err(ErrorKind::UnknownTarget(name.to_owned()).into()),
+    };
+    let mut checks = vec![];
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        checks.extend(zfs.verify_replica(target, snap)?);
+    }
+    if json {
+        println!("{}", json::as_pretty_json(&checks));
+    } else {
+        zfs::show_replica_checks(&checks);
+    }
+    Ok(())
+}
+
+fn do_check(config: &str, matches: &ArgMatches) -> Result<Check> {
+    let thresholds = Thresholds {
+        age: Limit {
+            warn: opt_value(matches, "warn-age", daemon::parse_interval)?,
+            crit: opt_value(matches, "crit-age", daemon::parse_interval)?,
+        },
+        lag: Limit {
+            warn: opt_value(matches, "warn-lag", parse_count)?,
+            crit: opt_value(matches, "crit-lag", parse_count)?,
+        },
+        sure: Limit {
+            warn: opt_value(matches, "warn-sure", parse_count)?,
+            crit: opt_value(matches, "crit-sure", parse_count)?,
+        },
+    };
+
+    let back = RBack {
+        host: load_host(config)?,
+        dry_run: true,
+        fail_fast: false,
+        report: Report::new(),
+    };
+    let roots = back.host.roots()?;
+
+    let mut check = Check::new();
+    let mut status = vec![];
+    let mut pools: Vec<&str> = vec![];
+    for root in &roots {
+        let zfs = ZFS::new(&back, root)?;
+        status.extend(zfs.status(true)?);
+        if !pools.contains(&root.pool()) {
+            pools.push(root.pool());
+            let props = zfs.get_pool_props()?;
+            check.pool(root.pool(), props.health().unwrap_or("UNKNOWN"));
+        }
+    }
+    check.datasets(&thresholds, &status);
+    Ok(check)
+}
+
+/// Parse an optional argument with the given parser.
+fn opt_value<F, E>(matches: &ArgMatches, name: &str, parse: F) -> Result<Option<u64>>
+    where F: Fn(&str) -> result::Result<i64, E>,
+          E: Into<Error>
+{
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(text) => Ok(Some(parse(text).map_err(|e| e.into())? as u64)),
+    }
+}
+
+fn parse_count(text: &str) -> Result<i64> {
+    match text.parse::<i64>() {
+        Ok(n) if n >= 0 => Ok(n),
+        _ => Err(format!("Invalid count: {:?}", text).into()),
+    }
+}
+
+fn do_props(back: &RBack, roots: &[Root], matches: &ArgMatches) -> Result<()> {
+    let props: Vec<_> = match matches.value_of("props").unwrap_or(DEFAULT_PROPS) {
+        "all" => vec![],
+        text => text.split(',').collect(),
+    };
+    let sources = match matches.value_of("source") {
+        None => vec![],
+        Some(text) => zfs::source_kinds(text)?,
+    };
+    let mut rows = vec![];
+    for root in roots {
+        let zfs = ZFS::new(back, root)?;
+        rows.extend(zfs.prop_rows(&props, matches.is_present("snapshots"), &sources)?);
+    }
+    if matches.is_present("json") {
+        println!("{}", json::as_pretty_json(&rows));
+    } else {
+        zfs::show_props(&rows);
+    }
     Ok(())
 }
 
@@ -0,0 +1,246 @@
+//! Metrics for the Prometheus node_exporter textfile collector.
+//!
+//! After each run, rback rewrites a `.prom` file with what it knows about the datasets, and what
+//! the run did.  Some metrics, such as the last success of each command, cover more than one
+//! run, so the previous file is read back in, and its samples kept unless this run replaces
+//! them.  The file is written under another name and renamed into place, so the collector never
+//! sees a partial file.
+
+use report::Summary;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use zfs::DataSetStatus;
+
+/// The metrics we write, with their type and help text.
+const METRICS: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("rback_last_success_timestamp_seconds", "gauge",
+     "When the last successful run of the command started."),
+    ("rback_last_failure_timestamp_seconds", "gauge",
+     "When the last run of the command that had errors started."),
+    ("rback_last_run_seconds", "gauge", "How long the last run of the command took."),
+    ("rback_newest_snapshot_timestamp_seconds", "gauge",
+     "When the newest snapshot of the dataset was taken."),
+    ("rback_snapshots", "gauge", "The number of snapshots of the dataset."),
+    ("rback_sure_snapshots", "gauge", "The snapshots of the dataset with sure data."),
+    ("rback_bksure_snapshots", "gauge",
+     "The snapshots of the dataset with sure data in the bksure store."),
+    ("rback_send_bytes", "gauge", "Bytes sent of the dataset in the last run that sent it."),
+    ("rback_send_seconds", "gauge", "Time spent sending the dataset in the last run that sent it."),
+    ("rback_pruned_snapshots_total", "counter", "Snapshots of the dataset destroyed by prune."),
+];
+
+/// The metrics that describe the datasets, which are replaced completely when the datasets are
+/// examined, so that removed datasets go away.
+const DATASET_METRICS: &'static [&'static str] = &[
+    "rback_newest_snapshot_timestamp_seconds",
+    "rback_snapshots",
+    "rback_sure_snapshots",
+    "rback_bksure_snapshots",
+];
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// The samples, indexed by the metric name with its labels.
+    samples: BTreeMap<String, f64>,
+}
+
+impl Metrics {
+    /// Read the samples from an existing metrics file.  A missing file has no samples.
+    pub fn load(path: &Path) -> io::Result<Metrics> {
+        let mut samples = BTreeMap::new();
+        match File::open(path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if line.starts_with('#') {
+                        continue;
+                    }
+                    if let Some(pos) = line.rfind(' ') {
+                        if let Ok(value) = line[pos + 1..].parse::<f64>() {
+                            samples.insert(line[..pos].to_owned(), value);
+                        }
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(Metrics { samples: samples })
+    }
+
+    /// Update the metrics with the result of a run, and the current state of the datasets.
+    pub fn update(&mut self, summary: &Summary, status: &[DataSetStatus]) {
+        let command = [("command", &summary.command[..])];
+        let outcome = if summary.status == "success" {
+            "rback_last_success_timestamp_seconds"
+        } else {
+            "rback_last_failure_timestamp_seconds"
+        };
+        self.set(outcome, &command, summary.started as f64);
+        self.set("rback_last_run_seconds", &command, summary.seconds);
+
+        // Sends of the same dataset within the run are added together.
+        let mut sent = BTreeMap::new();
+        for entry in &summary.entries {
+            let labels = [("dataset", &entry.dataset[..])];
+            match &entry.action[..] {
+                "send" | "resume" => {
+                    let dest = entry.dest.as_ref().map_or("", |x| &x[..]);
+                    let total = sent.entry((&entry.dataset[..], dest)).or_insert((0, 0.0));
+                    total.0 += entry.bytes.unwrap_or(0);
+                    total.1 += entry.seconds;
+                }
+                "destroy" if entry.error.is_none() && !summary.dry_run => {
+                    self.add("rback_pruned_snapshots_total", &labels,
+                             entry.snapshots.len() as f64);
+                }
+                _ => (),
+            }
+        }
+        for (&(dataset, dest), &(bytes, seconds)) in &sent {
+            let labels = [("dataset", dataset), ("dest", dest)];
+            self.set("rback_send_bytes", &labels, bytes as f64);
+            self.set("rback_send_seconds", &labels, seconds);
+        }
+
+        if !status.is_empty() {
+            for name in DATASET_METRICS {
+                self.clear(name);
+            }
+        }
+        for st in status {
+            let labels = [("dataset", &st.dataset[..])];
+            if let Some(created) = st.created {
+                self.set("rback_newest_snapshot_timestamp_seconds", &labels, created as f64);
+            }
+            self.set("rback_snapshots", &labels, st.snapshots as f64);
+            self.set("rback_sure_snapshots", &labels, st.sure as f64);
+            if let Some(bksure) = st.bksure {
+                self.set("rback_bksure_snapshots", &labels, bksure as f64);
+            }
+        }
+    }
+
+    fn set(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.samples.insert(sample_key(name, labels), value);
+    }
+
+    fn add(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        *self.samples.entry(sample_key(name, labels)).or_insert(0.0) += value;
+    }
+
+    /// Remove every sample of the named metric.
+    fn clear(&mut self, name: &str) {
+        let prefix = format!("{}{{", name);
+        let keys: Vec<_> = self.samples.keys()
+            .filter(|k| *k == name || k.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.samples.remove(&key);
+        }
+    }
+
+    /// Format the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut last_name = "";
+        for (key, value) in &self.samples {
+            let name = &key[..key.find('{').unwrap_or(key.len())];
+            if name != last_name {
+                if let Some(&(_, kind, help)) = METRICS.iter().find(|m| m.0 == name) {
+                    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+                }
+                last_name = name;
+            }
+            out.push_str(&format!("{} {}\n", key, value));
+        }
+        out
+    }
+
+    /// Write the metrics file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        // The collector only reads files ending in ".prom", so it ignores the temporary file.
+        let tmp = path.with_extension("prom.tmp");
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_all(self.render().as_bytes())?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+}
+
+fn sample_key(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_owned();
+    }
+    let labels: Vec<_> = labels.iter()
+        .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use report::{Entry, Report};
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+    use zfs::DataSetStatus;
+
+    fn status(dataset: &str, snapshots: usize) -> DataSetStatus {
+        DataSetStatus {
+            dataset: dataset.to_owned(),
+            snapshots: snapshots,
+            newest: None,
+            created: Some(1000),
+            age: None,
+            snap_used: None,
+            sure: 1,
+            sure_missing: vec![],
+            bksure: None,
+            bksure_missing: vec![],
+            replicas: vec![],
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let path = env::temp_dir().join("rback-test-metrics.prom");
+        let _ = fs::remove_file(&path);
+
+        let report = Report::new();
+        let ok: Result<(), String> = Ok(());
+        let destroy = Entry::new("tank/home", "destroy").snapshots(&["a", "b"]);
+        report.record(Instant::now(), destroy, &ok);
+        let send = Entry::new("tank/home", "send").dest("backup/home");
+        report.record_bytes(Instant::now(), send, &Ok::<u64, String>(1024));
+        let summary = report.finish("host", "prune", false, None);
+
+        let mut metrics = Metrics::load(&path).unwrap();
+        metrics.update(&summary, &[status("tank/home", 5), status("tank/old", 1)]);
+        metrics.write(&path).unwrap();
+
+        // A second run carries over the counters, and replaces the datasets.
+        let mut metrics = Metrics::load(&path).unwrap();
+        metrics.update(&summary, &[status("tank/home", 6)]);
+        let text = metrics.render();
+        fs::remove_file(&path).unwrap();
+
+        assert!(text.contains("# TYPE rback_pruned_snapshots_total counter\n"));
+        assert!(text.contains("rback_pruned_snapshots_total{dataset=\"tank/home\"} 4\n"));
+        assert!(text.contains("rback_send_bytes{dataset=\"tank/home\",dest=\"backup/home\"} 1024\n"));
+        assert!(text.contains("rback_snapshots{dataset=\"tank/home\"} 6\n"));
+        assert!(!text.contains("tank/old"));
+        assert!(text.contains(&format!("rback_last_success_timestamp_seconds{{command=\"prune\"}} {}\n",
+                                       summary.started)));
+    }
+}
@@ -0,0 +1,218 @@
+//! A record of what a run did.
+//!
+//! As rback works, it records each action it takes on a dataset (snapshots created, destroyed,
+//! hashed or sent), along with how long it took, and any error.  At the end of the run, this is
+//! written out as JSON, for the benefit of wrapper scripts, and determines the exit code.
+
+use chrono::UTC;
+use rustc_serialize::json;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::result;
+use std::time::{Duration, Instant};
+
+/// Everything worked.
+pub const EXIT_SUCCESS: i32 = 0;
+/// Nothing worked, or rback couldn't get started.
+pub const EXIT_FAILURE: i32 = 1;
+/// Some actions worked, and some failed.
+pub const EXIT_PARTIAL: i32 = 2;
+
+/// A single action taken on a dataset.
+#[derive(Debug, RustcEncodable)]
+pub struct Entry {
+    pub dataset: String,
+    /// One of "snapshot", "destroy", "sure", "bksure", "sure-gc", "send", "resume" or "verify".
+    pub action: String,
+    /// The snapshots acted upon.
+    pub snapshots: Vec<String>,
+    /// Where the snapshots were sent, for "send" and "resume".
+    pub dest: Option<String>,
+    /// How much data was moved (or removed, for "sure-gc"), when it is known.
+    pub bytes: Option<u64>,
+    pub seconds: f64,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new(dataset: &str, action: &str) -> Entry {
+        Entry {
+            dataset: dataset.to_owned(),
+            action: action.to_owned(),
+            snapshots: vec![],
+            dest: None,
+            bytes: None,
+            seconds: 0.0,
+            error: None,
+        }
+    }
+
+    pub fn snapshots<S: AsRef<str>>(mut self, snaps: &[S]) -> Entry {
+        self.snapshots = snaps.iter().map(|s| s.as_ref().to_owned()).collect();
+        self
+    }
+
+    pub fn dest(mut self, dest: &str) -> Entry {
+        self.dest = Some(dest.to_owned());
+        self
+    }
+}
+
+/// The actions of the current run.
+pub struct Report {
+    started: i64,
+    start: Instant,
+    entries: RefCell<Vec<Entry>>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report {
+            started: UTC::now().timestamp(),
+            start: Instant::now(),
+            entries: RefCell::new(vec![]),
+        }
+    }
+
+    /// Record an action, begun at `start`, with its result.
+    pub fn record<T, E: fmt::Display>(&self, start: Instant, mut entry: Entry,
+                                      result: &result::Result<T, E>) {
+        entry.seconds = seconds(start.elapsed());
+        if let Err(ref e) = *result {
+            entry.error = Some(e.to_string());
+        }
+        self.entries.borrow_mut().push(entry);
+    }
+
+    /// Record an action that moved `bytes` of data.
+    pub fn record_bytes<E: fmt::Display>(&self, start: Instant, mut entry: Entry,
+                                         result: &result::Result<u64, E>) {
+        if let Ok(bytes) = *result {
+            entry.bytes = Some(bytes);
+        }
+        self.record(start, entry, result)
+    }
+
+    /// The number of actions recorded so far that failed.
+    pub fn errors(&self) -> usize {
+        self.entries.borrow().iter().filter(|e| e.error.is_some()).count()
+    }
+
+    /// Finish the report of a run of `command`, given the error that ended it, if any.
+    pub fn finish(&self, host: &str, command: &str, dry_run: bool,
+                  error: Option<String>) -> Summary {
+        let entries = mem::replace(&mut *self.entries.borrow_mut(), vec![]);
+        let failed = error.is_some() || entries.iter().any(|e| e.error.is_some());
+        let worked = entries.iter().any(|e| e.error.is_none());
+        let (status, exit_code) = if !failed {
+            ("success", EXIT_SUCCESS)
+        } else if worked {
+            ("partial", EXIT_PARTIAL)
+        } else {
+            ("failure", EXIT_FAILURE)
+        };
+
+        Summary {
+            host: host.to_owned(),
+            command: command.to_owned(),
+            dry_run: dry_run,
+            started: self.started,
+            seconds: seconds(self.start.elapsed()),
+            status: status.to_owned(),
+            exit_code: exit_code,
+            error: error,
+            entries: entries,
+        }
+    }
+}
+
+/// The finished report, as written out.
+#[derive(Debug, RustcEncodable)]
+pub struct Summary {
+    pub host: String,
+    pub command: String,
+    pub dry_run: bool,
+    /// When the run started, in seconds since the epoch.
+    pub started: i64,
+    pub seconds: f64,
+    /// One of "success", "partial" or "failure".
+    pub status: String,
+    pub exit_code: i32,
+    /// The error that stopped the run.
+    pub error: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+impl Summary {
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{}", json::as_pretty_json(self))
+    }
+
+    /// Show the actions that failed, and why, if any did.
+    pub fn show_failures<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let failed: Vec<_> = self.entries.iter().filter(|e| e.error.is_some()).collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        writeln!(out, "{} of {} actions failed:", failed.len(), self.entries.len())?;
+        for entry in failed {
+            let mut what = format!("{} {}", entry.action, entry.dataset);
+            if !entry.snapshots.is_empty() {
+                what.push_str(&format!("@{}", entry.snapshots.join(",")));
+            }
+            if let Some(ref dest) = entry.dest {
+                what.push_str(&format!(" to {}", dest));
+            }
+            writeln!(out, "  {}: {}", what, entry.error.as_ref().unwrap())?;
+        }
+        Ok(())
+    }
+}
+
+fn seconds(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + dur.subsec_nanos() as f64 / 1.0e9
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    fn run(results: &[Result<(), &str>], error: Option<&str>) -> Summary {
+        let report = Report::new();
+        for (i, res) in results.iter().enumerate() {
+            let entry = Entry::new(&format!("pool/ds{}", i), "snapshot").snapshots(&["snap"]);
+            report.record(Instant::now(), entry, res);
+        }
+        report.finish("host", "snap", false, error.map(|e| e.to_owned()))
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(run(&[Ok(()), Ok(())], None).exit_code, EXIT_SUCCESS);
+        assert_eq!(run(&[], None).exit_code, EXIT_SUCCESS);
+        assert_eq!(run(&[Ok(()), Err("bad")], None).exit_code, EXIT_PARTIAL);
+        assert_eq!(run(&[Ok(())], Some("bad")).exit_code, EXIT_PARTIAL);
+        assert_eq!(run(&[Err("bad")], None).exit_code, EXIT_FAILURE);
+        assert_eq!(run(&[], Some("bad")).exit_code, EXIT_FAILURE);
+
+        let sum = run(&[Err("bad")], None);
+        assert_eq!(sum.status, "failure");
+        assert_eq!(sum.entries[0].error, Some("bad".to_owned()));
+        assert_eq!(sum.entries[0].snapshots, vec!["snap".to_owned()]);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut out = vec![];
+        run(&[Ok(()), Ok(())], None).show_failures(&mut out).unwrap();
+        assert!(out.is_empty());
+
+        run(&[Ok(()), Err("bad"), Err("worse")], None).show_failures(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "2 of 3 actions failed:\n  snapshot pool/ds1@snap: bad\n  \
+                    snapshot pool/ds2@snap: worse\n");
+    }
+}
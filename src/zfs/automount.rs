@@ -0,0 +1,193 @@
+//! Mounting snapshots to read them.
+//!
+//! The snapshots of a mounted dataset can be read under `.zfs/snapshot/<snap>` in its mount
+//! point.  zfs mounts each of these when it is first opened, and only unmounts them again after
+//! they have sat idle for a while, so a full sure pass would leave every snapshot it read
+//! mounted.  `SnapMount` opens the directory to mount it, checks in the mount table that it
+//! worked, and unmounts it again when done, unless it was mounted already.
+//!
+//! Datasets that aren't mounted, such as those with `mountpoint=none` or `legacy`, or
+//! `canmount=off`, have no `.zfs` directory to use.  Their snapshots are mounted read-only on a
+//! private temporary directory instead, which is removed again afterwards.
+
+use libc;
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::process::Command;
+use std::str;
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use super::Result;
+
+const MOUNTINFO: &'static str = "/proc/self/mountinfo";
+
+/// Distinguishes the temporary directories of this process.
+static NEXT_TEMP: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A snapshot directory, which stays mounted for as long as this is kept.
+#[derive(Debug)]
+pub struct SnapMount {
+    dir: String,
+    /// Was the snapshot mounted here, and so should be unmounted?
+    ours: bool,
+    /// Is `dir` our own temporary directory, to be removed once unmounted?
+    temp: bool,
+}
+
+impl SnapMount {
+    /// Mount the snapshot directory `dir`, if it isn't already.
+    pub fn open(dir: &str) -> Result<SnapMount> {
+        if is_mounted(dir)? {
+            return Ok(SnapMount {
+                dir: dir.to_owned(),
+                ours: false,
+                temp: false,
+            });
+        }
+
+        if let Err(e) = fs::read_dir(dir) {
+            return Err(format!("Unable to read snapshot directory {:?}: {} (the dataset must \
+                                be mounted, with snapdir not disabled)", dir, e).into());
+        }
+        if !is_mounted(dir)? {
+            return Err(format!("Snapshot directory {:?} was not mounted on opening it", dir)
+                       .into());
+        }
+        Ok(SnapMount {
+            dir: dir.to_owned(),
+            ours: true,
+            temp: false,
+        })
+    }
+
+    /// Mount `snapshot` (the full name, with the '@') read-only on a private temporary
+    /// directory.
+    pub fn private(snapshot: &str) -> Result<SnapMount> {
+        let mut mount = SnapMount {
+            dir: make_temp_dir()?,
+            ours: false,
+            temp: true,
+        };
+        let stat = Command::new("mount")
+            .args(&["-t", "zfs", "-o", "ro", snapshot, &mount.dir])
+            .status()?;
+        if !stat.success() {
+            return Err(format!("Unable to mount {} on {:?}: {:?}", snapshot, mount.dir, stat)
+                       .into());
+        }
+        mount.ours = true;
+        if !is_mounted(&mount.dir)? {
+            return Err(format!("{} was not mounted on {:?}", snapshot, mount.dir).into());
+        }
+        Ok(mount)
+    }
+
+    /// The directory holding the files of the snapshot.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+}
+
+impl Drop for SnapMount {
+    fn drop(&mut self) {
+        if self.ours {
+            // Nothing can be done about a failure, and zfs will unmount those in .zfs in time
+            // anyway.
+            match Command::new("umount").arg(&self.dir).status() {
+                Ok(ref stat) if stat.success() => (),
+                Ok(stat) => {
+                    println!("Unable to unmount {:?}: {:?}", self.dir, stat);
+                    return;
+                }
+                Err(e) => {
+                    println!("Unable to unmount {:?}: {}", self.dir, e);
+                    return;
+                }
+            }
+        }
+        if self.temp {
+            if let Err(e) = fs::remove_dir(&self.dir) {
+                println!("Unable to remove {:?}: {}", self.dir, e);
+            }
+        }
+    }
+}
+
+/// Make a new directory, that only we can use, to mount a snapshot on.
+fn make_temp_dir() -> Result<String> {
+    let pid = unsafe { libc::getpid() };
+    loop {
+        let num = NEXT_TEMP.fetch_add(1, Ordering::SeqCst);
+        let dir = env::temp_dir().join(format!("rback-{}-{}", pid, num));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir.to_string_lossy().into_owned()),
+            // Left over from an earlier run with the same pid.
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Is something mounted at `dir`?  The names are compared as paths, so that doubled or trailing
+/// slashes in `dir` don't matter.
+fn is_mounted(dir: &str) -> Result<bool> {
+    let mut text = String::new();
+    File::open(MOUNTINFO)?.read_to_string(&mut text)?;
+    Ok(mount_points(&text).iter().any(|m| Path::new(m) == Path::new(dir)))
+}
+
+/// The mount points listed in the text of a mountinfo file.  They are the fifth field of each
+/// line, with spaces, tabs, newlines and backslashes written in octal.
+fn mount_points(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_octal)
+        .collect()
+}
+
+fn unescape_octal(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'\\' && pos + 4 <= bytes.len() {
+            let code = str::from_utf8(&bytes[pos + 1..pos + 4]).ok()
+                .and_then(|c| u8::from_str_radix(c, 8).ok());
+            if let Some(ch) = code {
+                result.push(ch);
+                pos += 4;
+                continue;
+            }
+        }
+        result.push(bytes[pos]);
+        pos += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::mount_points;
+    use std::path::Path;
+
+    #[test]
+    fn test_mount_points() {
+        let text = "22 1 0:21 / /proc rw,nosuid - proc proc rw\n\
+                    85 60 0:45 / /home/db rw,relatime shared:40 - zfs tank/home/db rw,xattr\n\
+                    91 85 0:52 / /home/db/.zfs/snapshot/h-00002-10-01 ro,relatime shared:44 - \
+                    zfs tank/home/db@h-00002-10-01 ro\n\
+                    93 60 0:53 / /home/odd\\040name\\134x rw - zfs tank/home/odd rw\n\
+                    97 1 0:54 / /.zfs/snapshot/r-00001-10-01 ro - zfs rpool/root@r-00001-10-01 \
+                    ro\n";
+        let points = mount_points(text);
+        assert_eq!(points,
+                   vec!["/proc", "/home/db", "/home/db/.zfs/snapshot/h-00002-10-01",
+                        "/home/odd name\\x", "/.zfs/snapshot/r-00001-10-01"]);
+
+        // The snapshots of a dataset mounted at "/".
+        assert!(points.iter().any(|m| Path::new(m) == Path::new("//.zfs/snapshot/r-00001-10-01")));
+    }
+}
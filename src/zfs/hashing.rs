@@ -0,0 +1,398 @@
+//! Hashing many datasets at once.
+//!
+//! `run_sure` and `run_bksure` turn each dataset into a job: the snapshots that need sure data, in
+//! order, each using the one before it as the base for an incremental update.  The jobs are run by
+//! a pool of threads, each taking a whole dataset at a time, so that the order within a dataset is
+//! kept.  The results are sent back to the calling thread, which records them in the report, and
+//! the progress of all of the threads is shown as one.
+
+use openssl::crypto::hash::{Hasher, Type};
+use report::Entry;
+use rsure::{SureHash, SureTree};
+use rustc_serialize::hex::ToHex;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::automount::SnapMount;
+use super::props::Access;
+use super::relay::humanize;
+use super::{carry_on, check_stop, DataSet, Result};
+use RBack;
+
+/// Sure data to compute for a single snapshot.
+#[derive(Debug)]
+pub struct Step {
+    pub snap: String,
+    /// The sure data to update from, if any.
+    pub old: Option<String>,
+    /// Where to put the new sure data.
+    pub dest: String,
+}
+
+/// The snapshots of a dataset that need sure data.
+#[derive(Debug)]
+pub struct Job {
+    pub ds: DataSet,
+    /// How the snapshots can be read.
+    pub access: Access,
+    pub steps: Vec<Step>,
+}
+
+impl Job {
+    /// Mount a snapshot of the dataset, to read its files, for as long as the result is kept.
+    pub fn mount(&self, snap: &str) -> Result<SnapMount> {
+        match self.access {
+            Access::Snapdir(_) => SnapMount::open(&self.location(snap)),
+            Access::Mount => SnapMount::private(&format!("{}@{}", self.ds.name, snap)),
+            Access::Volume | Access::Unreachable(_) => {
+                Err(format!("The snapshots of {} can't be read", self.ds.name).into())
+            }
+        }
+    }
+
+    /// Where a snapshot of the dataset would be read, without mounting anything, as a dry run
+    /// shows it.
+    pub fn location(&self, snap: &str) -> String {
+        match self.access {
+            Access::Snapdir(ref mp) => {
+                // Joined as paths, so that a dataset mounted at "/" doesn't give "//.zfs".
+                Path::new(mp).join(".zfs/snapshot").join(snap).to_string_lossy().into_owned()
+            }
+            Access::Mount => format!("mount -t zfs -o ro {}@{} <temp dir>", self.ds.name, snap),
+            Access::Volume | Access::Unreachable(_) => "unreadable".to_owned(),
+        }
+    }
+}
+
+/// A finished step, sent back to the calling thread.
+struct Done {
+    dataset: String,
+    snap: String,
+    start: Instant,
+    result: Result<()>,
+}
+
+/// Run the jobs with `threads` threads, calling `work` for each step, and recording it in the
+/// report as `action`.  When a step fails, the steps after it in the same dataset are updated
+/// from the sure data it would have used, and the other datasets carry on.  With `--fail-fast`,
+/// no more steps are started, and the first error is returned.
+pub fn run_jobs<F>(back: &RBack, action: &str, jobs: Vec<Job>, threads: usize,
+                   work: F) -> Result<()>
+    where F: Fn(&Job, &Step, &Meter) -> Result<()> + Send + Sync + 'static
+{
+    let jobs = skip_unreachable(back, action, jobs)?;
+    let meter = Arc::new(Meter::new(action, jobs.len()));
+    let work = Arc::new(work);
+    let queue = Arc::new(Mutex::new(jobs.into_iter()));
+    let failed = Arc::new(AtomicBool::new(false));
+    let fail_fast = back.fail_fast;
+    let (tx, rx) = mpsc::channel();
+
+    let mut handles = vec![];
+    for _ in 0..cmp::max(threads, 1) {
+        let meter = meter.clone();
+        let work = work.clone();
+        let queue = queue.clone();
+        let failed = failed.clone();
+        let tx = tx.clone();
+        let action = action.to_owned();
+        handles.push(thread::spawn(move || {
+            loop {
+                let job = match queue.lock().unwrap().next() {
+                    Some(job) => job,
+                    None => break,
+                };
+                println!("Run {} on {:?} at {}", action, job.ds.name, job.ds.mount);
+                // The sure data that failed steps would have written, and what they would
+                // have updated from instead.
+                let mut replaced: HashMap<String, Option<String>> = HashMap::new();
+                for step in &job.steps {
+                    if failed.load(Ordering::SeqCst) || check_stop().is_err() {
+                        return;
+                    }
+                    let old = step.old.as_ref()
+                        .and_then(|old| replaced.get(old).cloned())
+                        .unwrap_or_else(|| step.old.clone());
+                    let step = Step {
+                        snap: step.snap.clone(),
+                        old: old,
+                        dest: step.dest.clone(),
+                    };
+                    let start = Instant::now();
+                    let result = work(&job, &step, &meter);
+                    if result.is_err() {
+                        replaced.insert(step.dest.clone(), step.old.clone());
+                        if fail_fast {
+                            failed.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    let _ = tx.send(Done {
+                        dataset: job.ds.name.clone(),
+                        snap: step.snap.clone(),
+                        start: start,
+                        result: result,
+                    });
+                }
+                meter.finish_dataset();
+            }
+        }));
+    }
+    drop(tx);
+
+    let mut error = None;
+    for done in rx {
+        let entry = Entry::new(&done.dataset, action).snapshots(&[&done.snap]);
+        back.report.record(done.start, entry, &done.result);
+        if let Err(e) = done.result {
+            if let Err(e) = carry_on(back, &format!("{}@{}", done.dataset, done.snap), e) {
+                error = error.or(Some(e));
+            }
+        }
+    }
+    for handle in handles {
+        if handle.join().is_err() {
+            error = error.or(Some(format!("A {} thread panicked", action).into()));
+        }
+    }
+    meter.flush();
+
+    match error {
+        Some(e) => Err(e),
+        None => check_stop(),
+    }
+}
+
+/// Take out the jobs for datasets whose snapshots can't be read.  Those that aren't volumes are
+/// recorded as failed.
+fn skip_unreachable(back: &RBack, action: &str, jobs: Vec<Job>) -> Result<Vec<Job>> {
+    let mut result = vec![];
+    for job in jobs {
+        match job.access {
+            Access::Volume => println!("Skip {}: a volume has no files to hash", job.ds.name),
+            Access::Unreachable(ref why) => {
+                let snaps: Vec<_> = job.steps.iter().map(|s| &s.snap).collect();
+                let entry = Entry::new(&job.ds.name, action).snapshots(&snaps);
+                let err = format!("Unable to reach the snapshots of {}: {}", job.ds.name, why);
+                let err: Result<()> = Err(err.into());
+                back.report.record(Instant::now(), entry, &err);
+                carry_on(back, &job.ds.name, err.unwrap_err())?;
+            }
+            Access::Snapdir(_) | Access::Mount => result.push(job),
+        }
+    }
+    Ok(result)
+}
+
+/// The combined progress of the threads.
+pub struct Meter {
+    action: String,
+    state: Mutex<MeterState>,
+}
+
+struct MeterState {
+    datasets: usize,
+    done: usize,
+    files: u64,
+    total_files: u64,
+    bytes: u64,
+    total_bytes: u64,
+    next_update: Instant,
+}
+
+impl Meter {
+    fn new(action: &str, datasets: usize) -> Meter {
+        Meter {
+            action: action.to_owned(),
+            state: Mutex::new(MeterState {
+                datasets: datasets,
+                done: 0,
+                files: 0,
+                total_files: 0,
+                bytes: 0,
+                total_bytes: 0,
+                next_update: Instant::now() + Duration::from_secs(5),
+            }),
+        }
+    }
+
+    /// Add to the work that is known to be needed.
+    fn estimate(&self, files: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.total_files += files;
+        state.total_bytes += bytes;
+    }
+
+    /// Note work that has been done, showing the progress every so often.
+    fn update(&self, files: u64, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.files += files;
+        state.bytes += bytes;
+        if Instant::now() > state.next_update {
+            self.show(&mut state);
+        }
+    }
+
+    fn finish_dataset(&self) {
+        self.state.lock().unwrap().done += 1;
+    }
+
+    fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.show(&mut state);
+    }
+
+    fn show(&self, state: &mut MeterState) {
+        println!("{}: {}/{} datasets, {}/{} files, {}/{}", self.action, state.done,
+                 state.datasets, state.files, state.total_files, humanize(state.bytes),
+                 humanize(state.total_bytes));
+        state.next_update = Instant::now() + Duration::from_secs(5);
+    }
+}
+
+// From linux's fcntl.h, as rsure has it.
+const O_NOATIME: i32 = 0o1000000;
+
+/// Hash the files in the tree that don't have hashes yet, reading them from `path`, as rsure's
+/// `hash_update` would.  The files are hashed here, rather than by rsure, as it only reports
+/// progress with lines of its own, and only after each file.  Instead, the meter is given the
+/// progress as each file is read.
+pub fn hash_tree(tree: &mut SureTree, path: &Path, meter: &Meter) {
+    let est = tree.hash_estimate();
+    meter.estimate(est.files, est.bytes);
+    hash_dir(tree, path, meter);
+}
+
+fn hash_dir(tree: &mut SureTree, path: &Path, meter: &Meter) {
+    for file in &mut tree.files {
+        let needs_hash = file.atts.get("kind").map_or(false, |k| k == "file") &&
+            !file.atts.contains_key("sha1");
+        if !needs_hash {
+            continue;
+        }
+        let name: OsString = OsStringExt::from_vec(unescape(&file.name));
+        let fpath = path.join(&name);
+        // As with rsure, a file that can't be read is left without a hash.
+        match hash_file(&fpath, meter) {
+            Ok(hash) => {
+                file.atts.insert("sha1".to_owned(), hash.to_hex());
+            }
+            Err(e) => println!("Unable to hash {:?}: {}", fpath, e),
+        }
+        meter.update(1, 0);
+    }
+
+    for child in &mut tree.children {
+        let name: OsString = OsStringExt::from_vec(unescape(&child.name));
+        let cpath = path.join(&name);
+        hash_dir(child, &cpath, meter);
+    }
+}
+
+/// The sha1 of the file at `path`, giving the meter the bytes as they are read.
+fn hash_file(path: &Path, meter: &Meter) -> io::Result<Vec<u8>> {
+    // Reading the snapshots shouldn't change the access times of anything.
+    let mut fd = match OpenOptions::new().read(true).custom_flags(O_NOATIME).open(path) {
+        Ok(fd) => fd,
+        Err(_) => File::open(path)?,
+    };
+    let mut hasher = Hasher::new(Type::SHA1);
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let count = fd.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        hasher.write_all(&buf[..count])?;
+        meter.update(0, count as u64);
+    }
+    Ok(hasher.finish())
+}
+
+/// Undo the escaping of a name in a sure tree, where bytes can be written as "=xx".
+fn unescape(name: &str) -> Vec<u8> {
+    let bytes = name.as_bytes();
+    let mut result = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'=' && pos + 3 <= bytes.len() {
+            if let Ok(ch) = u8::from_str_radix(&name[pos + 1..pos + 3], 16) {
+                result.push(ch);
+                pos += 3;
+                continue;
+            }
+        }
+        result.push(bytes[pos]);
+        pos += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash_tree, unescape, Job, Meter};
+    use libc;
+    use rsure;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use zfs::{local_path, DataSet};
+    use zfs::props::Access;
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape("plain"), b"plain".to_vec());
+        assert_eq!(unescape("odd=20name=3d"), b"odd name=".to_vec());
+        assert_eq!(unescape("=ff=0a"), vec![0xff, b'\n']);
+    }
+
+    #[test]
+    fn test_hash_tree() {
+        let dir = env::temp_dir().join(format!("rback-hash-{}", unsafe { libc::getpid() }));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        File::create(dir.join("sub/abc")).unwrap().write_all(b"abc").unwrap();
+        File::create(dir.join("empty")).unwrap();
+
+        let mut tree = rsure::scan_fs(&dir).unwrap();
+        let meter = Meter::new("sure", 1);
+        hash_tree(&mut tree, &dir, &meter);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let abc = &tree.children[0].files[0];
+        assert_eq!(abc.atts["sha1"], "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(tree.files[0].atts["sha1"], "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        let state = meter.state.lock().unwrap();
+        assert_eq!((state.files, state.total_files), (2, 2));
+        assert_eq!((state.bytes, state.total_bytes), (3, 3));
+    }
+
+    #[test]
+    fn test_location() {
+        let job = |name: &str, access: Access| Job {
+            ds: DataSet {
+                dir: local_path(name),
+                name: name.to_owned(),
+                snaps: vec![],
+                mount: "-".to_owned(),
+            },
+            access: access,
+            steps: vec![],
+        };
+        assert_eq!(job("tank/home", Access::Snapdir("/home".to_owned())).location("h-1"),
+                   "/home/.zfs/snapshot/h-1");
+        assert_eq!(job("rpool/root", Access::Snapdir("/".to_owned())).location("h-1"),
+                   "/.zfs/snapshot/h-1");
+        assert_eq!(job("tank/db", Access::Mount).location("h-1"),
+                   "mount -t zfs -o ro tank/db@h-1 <temp dir>");
+    }
+}
@@ -0,0 +1,236 @@
+//! Hook commands run around taking snapshots.
+//!
+//! Hooks can be given for a whole root (or host), and for datasets within it.  Each command is
+//! run with `/bin/sh -c`, with these environment variables set:
+//!
+//! * `RBACK_HOOK`: "pre", "post" or "failure".
+//! * `RBACK_SNAPSHOT`: the name of the snapshot being taken of the root the hook belongs to (the
+//!   part after the '@').  The roots snapshotted together can name their snapshots differently.
+//! * `RBACK_SNAPSHOTS`: the full names given to `zfs snapshot`, separated by spaces.
+//! * `RBACK_DATASETS`: every dataset being snapshotted, separated by spaces.
+//! * `RBACK_DATASET`: for dataset hooks, the dataset the hook was given for.
+//! * `RBACK_ERROR`: for failure hooks, what went wrong.
+//!
+//! Each command is run in a process group of its own, so that when it runs past its timeout,
+//! whatever it started is killed along with it.
+
+use config::{Hooks, Root};
+use libc;
+use std::fmt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{own_process_group, Result};
+
+// How long hooks may run, unless the config says otherwise.
+const DEFAULT_TIMEOUT: u64 = 60;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Pre,
+    Post,
+    Failure,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Stage::Pre => "pre",
+            Stage::Post => "post",
+            Stage::Failure => "failure",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What the hooks are told about the snapshot.
+pub struct HookEnv<'e> {
+    pub snapshots: &'e [String],
+    pub datasets: &'e [String],
+    pub error: Option<String>,
+}
+
+/// A set of hooks, and the dataset they were given for (None for the whole root).
+pub struct Hook<'a> {
+    hooks: &'a Hooks,
+    /// The base of the root the hooks belong to.
+    base: &'a str,
+    dataset: Option<String>,
+}
+
+impl<'a> Hook<'a> {
+    /// Gather the hooks of a root, and of the datasets configured within it.
+    pub fn for_root(root: &'a Root) -> Vec<Hook<'a>> {
+        let mut result = vec![];
+        if let Some(ref hooks) = root.hooks {
+            result.push(Hook {
+                hooks: hooks,
+                base: &root.base,
+                dataset: None,
+            });
+        }
+        if let Some(ref datasets) = root.datasets {
+            for ds in datasets {
+                if let Some(ref hooks) = ds.hooks {
+                    let dataset = if ds.name.is_empty() {
+                        root.base.clone()
+                    } else {
+                        format!("{}/{}", root.base, ds.name)
+                    };
+                    result.push(Hook {
+                        hooks: hooks,
+                        base: &root.base,
+                        dataset: Some(dataset),
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    /// Should a failure of the pre hook prevent the snapshot?
+    pub fn abort_on_pre_failure(&self) -> Result<bool> {
+        match self.hooks.pre_failure.as_ref().map(|x| &x[..]) {
+            None | Some("abort") => Ok(true),
+            Some("continue") => Ok(false),
+            Some(other) => Err(format!("Unknown pre_failure policy: {:?}", other).into()),
+        }
+    }
+
+    /// Run the command for the given stage, if there is one.  A command that exits with an
+    /// error, or runs past the timeout, is a failure.
+    pub fn run(&self, stage: Stage, env: &HookEnv, dry_run: bool) -> Result<()> {
+        let text = match stage {
+            Stage::Pre => &self.hooks.pre,
+            Stage::Post => &self.hooks.post,
+            Stage::Failure => &self.hooks.failure,
+        };
+        let text = match *text {
+            None => return Ok(()),
+            Some(ref text) => text,
+        };
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", text]);
+        cmd.env("RBACK_HOOK", stage.to_string());
+        let snapshot = env.snapshots.iter().find(|s| s.splitn(2, '@').next() == Some(self.base));
+        if let Some(snapshot) = snapshot {
+            cmd.env("RBACK_SNAPSHOT", snapshot.splitn(2, '@').nth(1).unwrap_or(""));
+        }
+        cmd.env("RBACK_SNAPSHOTS", env.snapshots.join(" "));
+        cmd.env("RBACK_DATASETS", env.datasets.join(" "));
+        if let Some(ref dataset) = self.dataset {
+            cmd.env("RBACK_DATASET", dataset);
+        }
+        if let Some(ref error) = env.error {
+            cmd.env("RBACK_ERROR", error);
+        }
+
+        if dry_run {
+            println!("Would run {} hook: {:?}", stage, text);
+            return Ok(());
+        }
+        println!("Run {} hook: {:?}", stage, text);
+
+        let timeout = Duration::from_secs(self.hooks.timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let start = Instant::now();
+        own_process_group(&mut cmd);
+        let mut child = cmd.spawn()?;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                if status.success() {
+                    return Ok(());
+                }
+                return Err(format!("{} hook {:?} failed: {:?}", stage, text, status).into());
+            }
+            if start.elapsed() >= timeout {
+                // The whole group, as the shell may have started other commands.
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                child.wait()?;
+                return Err(format!("{} hook {:?} timed out after {} seconds",
+                                   stage, text, timeout.as_secs()).into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Hooks;
+    use libc;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn hooks(pre: &str, timeout: Option<u64>) -> Hooks {
+        Hooks {
+            pre: Some(pre.to_owned()),
+            post: None,
+            failure: None,
+            timeout: timeout,
+            pre_failure: None,
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let names = vec!["pool/b@other-00007".to_owned(), "pool/a@snap-00001".to_owned()];
+        let datasets = vec!["pool/a".to_owned(), "pool/a/db".to_owned()];
+        let env = HookEnv {
+            snapshots: &names,
+            datasets: &datasets,
+            error: None,
+        };
+
+        let good = hooks("test \"$RBACK_SNAPSHOT\" = snap-00001 && \
+                          test \"$RBACK_DATASETS\" = 'pool/a pool/a/db' && \
+                          test \"$RBACK_HOOK\" = pre", None);
+        let hook = Hook { hooks: &good, base: "pool/a", dataset: None };
+        hook.run(Stage::Pre, &env, false).unwrap();
+        assert!(hook.abort_on_pre_failure().unwrap());
+        // Stages without a command do nothing.
+        hook.run(Stage::Post, &env, false).unwrap();
+
+        let bad = hooks("exit 1", None);
+        assert!(Hook { hooks: &bad, base: "pool/a", dataset: None }.run(Stage::Pre, &env, false).is_err());
+
+        let slow = hooks("sleep 5", Some(0));
+        assert!(Hook { hooks: &slow, base: "pool/a", dataset: None }
+                .run(Stage::Pre, &env, false).is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        // What the hook started is killed along with it.
+        let pidfile = env::temp_dir().join(format!("rback-hook-{}", unsafe { libc::getpid() }));
+        let slow = hooks(&format!("sleep 30 & echo $! > {}; wait", pidfile.display()), Some(1));
+        let env = HookEnv {
+            snapshots: &[],
+            datasets: &[],
+            error: None,
+        };
+        assert!(Hook { hooks: &slow, base: "pool/a", dataset: None }
+                .run(Stage::Pre, &env, false).is_err());
+
+        let mut text = String::new();
+        File::open(&pidfile).unwrap().read_to_string(&mut text).unwrap();
+        fs::remove_file(&pidfile).unwrap();
+        let stat = format!("/proc/{}/stat", text.trim());
+        for _ in 0..50 {
+            // Gone, or at least dead and waiting to be reaped.
+            let mut text = String::new();
+            match File::open(&stat).and_then(|mut f| f.read_to_string(&mut text)) {
+                Err(_) => return,
+                Ok(_) if text.contains(") Z ") => return,
+                Ok(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        panic!("The hook's sleep is still running");
+    }
+}
@@ -1,21 +1,41 @@
 // ZFS support
 
-use chrono::{Datelike, Local};
-use regex::{self, Regex};
-use rsure::{self, Progress, SureHash, TreeUpdate};
+use libc;
+use regex::Regex;
+use rsure::{self, SureTree, TreeUpdate};
 use rsure::bk::BkDir;
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry;
 use std::fmt;
 use std::path::Path;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
-use std::rc::Rc;
 use std::string;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+mod automount;
+mod hashing;
+mod hooks;
+mod naming;
 mod props;
+mod prune;
+mod relay;
+mod retain;
+mod status;
+mod suregc;
+mod verify;
+
+use self::hashing::{Job, Step};
+use self::hooks::{Hook, HookEnv, Stage};
+use self::naming::Naming;
+use self::props::Access;
+use self::relay::Relay;
+
+pub use self::props::{show_props, source_kinds, PropRow};
+pub use self::status::{show_status, DataSetStatus, ReplicaStatus};
+pub use self::verify::{show_replica_checks, show_verify, ReplicaCheck, SnapChanges};
 
 error_chain! {
     types {
@@ -32,16 +52,20 @@ error_chain! {
     }
 
     errors {
+        Stopped {
+            description("Stopped")
+            display("Stopped, as requested")
+        }
     }
 }
 
 use RBack;
-
-// For pruning, always keep at least this many of the pruned snapshots.
-const PRUNE_KEEP: usize = 10;
+use config::{Root, Target};
+use daemon;
+use report::Entry;
 
 // A snap destination is somewhere that has a ZFS filesystem.
-pub trait ZfsPath: fmt::Debug {
+pub trait ZfsPath: fmt::Debug + Send + Sync {
     /// Retrieve the local path name of this ZfsPath.  With no mount
     /// options, this will be the same as the directory name, but without a
     /// leading slash.
@@ -49,15 +73,32 @@ pub trait ZfsPath: fmt::Debug {
 
     /// Construct a zfs command to access this path.
     fn command(&self) -> Command;
+
+    /// Construct a command to run a shell script on the host of this
+    /// path.
+    fn shell(&self, script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(&["-c", script]);
+        cmd
+    }
 }
 
 impl ZfsPath {
     /// Parse the given path, returning a trait object for ZfsPath that is
     /// either local or remote depending on the user's desire.
-    pub fn parse(text: &str) -> Rc<ZfsPath> {
+    pub fn parse(text: &str) -> Arc<ZfsPath> {
+        ZfsPath::parse_ssh(text, &[])
+    }
+
+    /// Like `parse`, but a remote path will pass the given extra options
+    /// to ssh.
+    pub fn parse_ssh(text: &str, ssh_options: &[String]) -> Arc<ZfsPath> {
         match ZfsRemotePath::parse(text) {
-            Some(zp) => Rc::new(zp),
-            None => Rc::new(ZfsLocalPath(text.to_owned())),
+            Some(mut zp) => {
+                zp.ssh_options = ssh_options.to_vec();
+                Arc::new(zp)
+            }
+            None => Arc::new(ZfsLocalPath(text.to_owned())),
         }
     }
 }
@@ -95,6 +136,8 @@ pub struct ZfsRemotePath {
     host: String,
     /// The zfs path name itself.
     path: String,
+    /// Extra options given to ssh before the host.
+    ssh_options: Vec<String>,
 }
 
 impl ZfsRemotePath {
@@ -109,6 +152,7 @@ impl ZfsRemotePath {
         Some(ZfsRemotePath {
             host: parts[0].to_owned(),
             path: parts[1].to_owned(),
+            ssh_options: vec![],
         })
     }
 }
@@ -120,34 +164,210 @@ impl ZfsPath for ZfsRemotePath {
 
     fn command(&self) -> Command {
         let mut cmd = Command::new("ssh");
+        cmd.args(&self.ssh_options);
         cmd.args(&[&self.host[..], "zfs"]);
         cmd
     }
+
+    fn shell(&self, script: &str) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.args(&self.ssh_options);
+        cmd.args(&[&self.host[..], script]);
+        cmd
+    }
+}
+
+/// Fail if the daemon has been asked to stop.  This is checked before each step that would be a
+/// shame to interrupt part way through.
+fn check_stop() -> Result<()> {
+    if daemon::stop_requested() {
+        return Err(ErrorKind::Stopped.into());
+    }
+    Ok(())
+}
+
+/// Start the command in a process group of its own, so that it can be killed along with
+/// everything it starts, by signalling the group.  This also keeps signals sent to our own group,
+/// such as the SIGINT of a Ctrl-C, from reaching it.
+fn own_process_group(cmd: &mut Command) {
+    unsafe {
+        cmd.before_exec(|| {
+            if libc::setpgid(0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Deal with the failure of the work on a single dataset, which has been recorded in the report.
+/// Unless running with `--fail-fast`, or asked to stop, the error is shown, and the run carries on
+/// with the other datasets.  The failures are summed up at the end.
+fn carry_on(back: &RBack, dataset: &str, err: Error) -> Result<()> {
+    match *err.kind() {
+        ErrorKind::Stopped => return Err(err),
+        _ if back.fail_fast => return Err(err),
+        _ => (),
+    }
+    println!("Error on {}, carrying on: {}", dataset, err);
+    Ok(())
 }
 
 // A utility for wrapping up a local path
-pub fn local_path(dir: &str) -> Rc<ZfsPath> {
-    Rc::new(ZfsLocalPath(dir.to_owned()))
+pub fn local_path(dir: &str) -> Arc<ZfsPath> {
+    Arc::new(ZfsLocalPath(dir.to_owned()))
+}
+
+/// Take the next snapshot of each of the roots.  zfs takes all of the snapshots given to a
+/// single command atomically, as long as they are in the same pool, so the roots are grouped by
+/// pool.  The hooks of every root in a pool are run around its snapshot.
+pub fn take_snapshots(zfss: &[&ZFS]) -> Result<()> {
+    let mut pools: Vec<SnapGroup> = vec![];
+    for zfs in zfss {
+        let snaps = zfs.get_snaps(local_path(&zfs.base()))?;
+        let name = zfs.next_snapshot_name(&snaps);
+        let pool = zfs.root.pool();
+        let pos = match pools.iter().position(|p| p.pool == pool) {
+            Some(pos) => pos,
+            None => {
+                pools.push(SnapGroup {
+                    back: zfs.back,
+                    pool: pool,
+                    names: vec![],
+                    datasets: vec![],
+                    hooks: vec![],
+                });
+                pools.len() - 1
+            }
+        };
+        let group = &mut pools[pos];
+        group.names.push(name);
+        group.datasets.extend(snaps.iter().map(|ds| ds.name.clone()));
+        group.hooks.extend(Hook::for_root(zfs.root));
+    }
+
+    for group in &pools {
+        group.snapshot()?;
+    }
+    Ok(())
+}
+
+// The snapshots to take with a single zfs command.
+struct SnapGroup<'a> {
+    back: &'a RBack,
+    pool: &'a str,
+    names: Vec<String>,
+    datasets: Vec<String>,
+    hooks: Vec<Hook<'a>>,
+}
+
+impl<'a> SnapGroup<'a> {
+    fn snapshot(&self) -> Result<()> {
+        let start = Instant::now();
+        let dry_run = self.back.dry_run;
+        let mut aborts = vec![];
+        for hook in &self.hooks {
+            aborts.push(hook.abort_on_pre_failure()?);
+        }
+
+        let env = HookEnv {
+            snapshots: &self.names,
+            datasets: &self.datasets,
+            error: None,
+        };
+
+        // Count the hooks whose pre hook has run, as these need their post hook run.
+        let mut ran = 0;
+        let mut failure = None;
+        for (hook, &abort) in self.hooks.iter().zip(&aborts) {
+            ran += 1;
+            if let Err(e) = hook.run(Stage::Pre, &env, dry_run) {
+                if abort {
+                    failure = Some(e);
+                    break;
+                }
+                println!("Warning: {}, taking snapshot anyway", e);
+            }
+        }
+
+        if failure.is_none() {
+            failure = self.run_snapshot(dry_run).err();
+        }
+        let result = failure.as_ref().map_or(Ok(()), Err);
+        for name in &self.names {
+            let mut parts = name.splitn(2, '@');
+            let entry = Entry::new(parts.next().unwrap(), "snapshot")
+                .snapshots(&parts.collect::<Vec<_>>());
+            self.back.report.record(start, entry, &result);
+        }
+
+        // Post hooks undo the pre hooks, so they are run in reverse order, and whether or not
+        // the snapshot worked.
+        let mut post_failure = None;
+        for hook in self.hooks[..ran].iter().rev() {
+            if let Err(e) = hook.run(Stage::Post, &env, dry_run) {
+                println!("Error: {}", e);
+                if post_failure.is_none() {
+                    post_failure = Some(e);
+                }
+            }
+        }
+
+        match failure {
+            Some(e) => {
+                let env = HookEnv { error: Some(e.to_string()), ..env };
+                for hook in &self.hooks {
+                    if let Err(e) = hook.run(Stage::Failure, &env, dry_run) {
+                        println!("Error: {}", e);
+                    }
+                }
+                Err(e)
+            }
+            None => match post_failure {
+                Some(e) => Err(e),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn run_snapshot(&self, dry_run: bool) -> Result<()> {
+        let mut cmd = Command::new("zfs");
+        cmd.args(&["snapshot", "-r"]);
+        cmd.args(&self.names);
+        if dry_run {
+            println!("Would run: {:?}", cmd);
+        } else {
+            println!("Run: {:?}", cmd);
+            let stat = cmd.status()?;
+            if !stat.success() {
+                return Err(format!("Unable to run zfs command: {:?}", stat).into());
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct ZFS<'a> {
     back: &'a RBack,
-    snap_re: Regex,
+    root: &'a Root,
+    naming: Naming,
     send_size_re: Regex,
 }
 
 impl<'a> ZFS<'a> {
-    pub fn new<'b>(back: &'b RBack) -> ZFS<'b> {
-        let quoted = regex::quote(&back.host.snap_prefix);
-        let pat = format!("^{}(\\d+)[-\\.]([-\\.\\d]+)$", quoted);
-        ZFS {
+    /// Construct a ZFS to manage the given root, which should have come from `Host::roots`.
+    pub fn new<'b>(back: &'b RBack, root: &'b Root) -> Result<ZFS<'b>> {
+        let naming = Naming::new(root.snap_prefix(),
+                                 root.snap_template.as_ref().map(|x| &x[..]))?;
+        Ok(ZFS {
             back: back,
-            snap_re: Regex::new(&pat).unwrap(),
+            root: root,
+            naming: naming,
             send_size_re: Regex::new(r"(?s).*\nsize\t(\d+)\n$").unwrap(),
-        }
+        })
     }
 
-    pub fn get_snaps(&self, dir: Rc<ZfsPath>) -> Result<Vec<DataSet>> {
+    pub fn get_snaps(&self, dir: Arc<ZfsPath>) -> Result<Vec<DataSet>> {
         let mut cmd = dir.command();
         cmd.args(&["list", "-H", "-t", "all", "-o", "name,mountpoint",
                  "-r", dir.name()]);
@@ -194,12 +414,11 @@ impl<'a> ZFS<'a> {
         let mut next = 0u32;
         for ds in sets {
             for sn in &ds.snaps {
-                match self.snap_re.captures(sn) {
+                match self.naming.parse(sn) {
                     None => (),
-                    Some(caps) => {
-                        let num = caps.at(1).unwrap().parse::<u32>().unwrap();
-                        if num > next {
-                            next = num;
+                    Some(parsed) => {
+                        if parsed.num > next {
+                            next = parsed.num;
                         }
                     },
                 }
@@ -208,234 +427,286 @@ impl<'a> ZFS<'a> {
         next + 1
     }
 
+    /// The full name of the next snapshot to take of this root, given its current snapshots.
+    pub fn next_snapshot_name(&self, snaps: &[DataSet]) -> String {
+        let num = self.next_snap(snaps);
+        format!("{}@{}", self.base(), self.naming.format(num))
+    }
+
     /// Take the next snapshot.
     pub fn take_snapshot(&self) -> Result<()> {
-        let snaps = self.get_snaps(local_path(&self.base()))?;
-        let num = self.next_snap(&snaps);
-        let today = Local::today();
-        let name = format!("{}@{}{:05}-{:02}-{:02}", self.base(),
-                           self.back.host.snap_prefix, num,
-                           today.month(), today.day());
-
-        let mut cmd = Command::new("zfs");
-        cmd.args(&["snapshot", "-r", &name]);
-        if self.back.dry_run {
-            println!("Would run: {:?}", cmd);
-        } else {
-            println!("Run: {:?}", cmd);
-            let stat = cmd.status()?;
-            if !stat.success() {
-                return Err(format!("Unable to run zfs command: {:?}", stat).into());
-            }
-        }
-        Ok(())
+        take_snapshots(&[self])
     }
 
-    /// Get all of the filesystems we care about, and update 'sure' data for all of them.
-    pub fn run_sure(&self) -> Result<()> {
+    /// Get all of the filesystems we care about, and update 'sure' data for all of them.  Up to
+    /// `threads` datasets are hashed at once.
+    pub fn run_sure(&self, threads: usize) -> Result<()> {
         let base = self.base();
 
         let snaps = self.get_nonsure_snaps(base)?;
-
-        for ds in snaps {
-            println!("Run sure on {:?} at {}", ds.name, ds.mount);
-
-            let mut last = None;
-            let subname = &ds.name[base.len()+1..];
-            // println!("  sub: {:?}", subname);
-            for snap in &ds.snaps {
-                let name = format!("/{}/sure/{}-{}.dat.gz", base, subname, snap);
-                if Path::new(&name).is_file() {
+        let subnames: Vec<_> = snaps.iter().map(|ds| &ds.name[base.len()+1..]).collect();
+        let names = self.sure_names(&subnames)?;
+
+        let mut jobs = vec![];
+        let access = self.snap_access(&snaps);
+        for (ds, access) in snaps.into_iter().zip(access) {
+            let mut steps = vec![];
+            {
+                let subname = &ds.name[base.len()+1..];
+                // If prune has destroyed every snapshot with sure data, start from the data kept
+                // from the newest of them.
+                let mut last = suregc::kept_base(&names, subname, &ds)
+                    .map(|n| n.path.to_string_lossy().into_owned());
+                for snap in &ds.snaps {
+                    let name = format!("/{}/sure/{}-{}.dat.gz", base, subname, snap);
+                    if !Path::new(&name).is_file() {
+                        steps.push(Step {
+                            snap: snap.clone(),
+                            old: last.clone(),
+                            dest: name.clone(),
+                        });
+                    }
                     last = Some(name);
-                    continue;
                 }
+            }
+            if !steps.is_empty() {
+                jobs.push(Job {
+                    access: access,
+                    ds: ds,
+                    steps: steps,
+                });
+            }
+        }
 
-                // println!("  {:?}", name);
-                let dir = format!("{}/.zfs/snapshot/{}", ds.mount, snap);
-
-                // The zfs snapshot automounter is a bit peculiar.  To ensure the directory is
-                // actually mounted, run a command in that directory.
-                self.ensure_dir(&dir)?;
-
-                match last {
-                    None => self.full_sure(&dir, &name)?,
-                    Some(ref old_name) => self.incremental_sure(&dir, old_name, &name)?,
-                }
-                if self.back.dry_run {
-                } else {
+        let dry_run = self.back.dry_run;
+        hashing::run_jobs(self.back, "sure", jobs, threads, move |job, step, meter| {
+            // Unmounted again once hashed, if it was mounted for this.  A dry run mounts
+            // nothing.
+            let mount = if dry_run { None } else { Some(job.mount(&step.snap)?) };
+            let dir = match mount {
+                Some(ref mount) => mount.dir().to_owned(),
+                None => job.location(&step.snap),
+            };
+            match step.old {
+                None => println!("  % sure -f {} ({})", step.dest, dir),
+                Some(ref old) => println!("  % sure --old {} -f {} ({})", old, step.dest, dir),
+            }
+            if !dry_run {
+                let mut tree = rsure::scan_fs(&dir)?;
+                if let Some(ref old) = step.old {
+                    tree.update_from(&SureTree::load(old)?);
                 }
-
-                last = Some(name);
-
+                hashing::hash_tree(&mut tree, Path::new(&dir), meter);
+                tree.save(&step.dest)?;
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     /// Update sure for all filesystems we care about, and update the
-    /// 'sure' data within a bksure store.
-    pub fn run_bksure(&self) -> Result<()> {
+    /// 'sure' data within a bksure store.  Up to `threads` datasets are
+    /// hashed at once, although only one uses the store at a time.
+    pub fn run_bksure(&self, threads: usize) -> Result<()> {
         let base = self.base();
         let snaps = self.get_nonsure_snaps(base)?;
         let bkd = BkDir::new(&format!("/{}/bksure", base))?;
         let present = bkd.query()?;
-        for ds in snaps {
-            let mut last = None;
-            let subname = &ds.name[base.len()+1..];
-            let datname = format!("{}.dat", subname);
-            let exists = present.iter()
-                .filter(|x| x.file == datname)
-                .map(|x| (&x.name[..]))
-                .collect::<HashSet<_>>();
-            // println!("  subname: {:?}", subname);
-            // println!("  exists: {:#?}", exists);
-            println!("Run bksure on {:?} at {}", ds.name, ds.mount);
-            for snap in &ds.snaps {
-                if exists.contains(&snap[..]) {
-                    last = Some(snap.to_owned());
-                    continue;
+        let mut jobs = vec![];
+        let access = self.snap_access(&snaps);
+        for (ds, access) in snaps.into_iter().zip(access) {
+            let mut steps = vec![];
+            {
+                let mut last = None;
+                let subname = &ds.name[base.len()+1..];
+                let datname = format!("{}.dat", subname);
+                let exists = present.iter()
+                    .filter(|x| x.file == datname)
+                    .map(|x| (&x.name[..]))
+                    .collect::<HashSet<_>>();
+                for snap in &ds.snaps {
+                    if !exists.contains(&snap[..]) {
+                        steps.push(Step {
+                            snap: snap.clone(),
+                            old: last.clone(),
+                            dest: snap.clone(),
+                        });
+                    }
+                    last = Some(snap.clone());
                 }
-
-                let dir = format!("{}/.zfs/snapshot/{}", ds.mount, snap);
-
-                // The zfs snapshot automounter is a bit peculiar.  To
-                // ensure the directory is actually mounted, run a command
-                // in that directory.
-                self.ensure_dir(&dir)?;
-
-                self.bksure(&bkd, &dir, &datname, last.as_ref().map(|x| x.as_str()), &snap)?;
-                last = Some(snap.clone());
             }
-        }
-        Ok(())
-    }
-
-    fn ensure_dir(&self, dir: &str) -> Result<()> {
-        let mut cmd = Command::new("pwd");
-        cmd.current_dir(dir);
-        let stat = cmd.status()?;
-        if !stat.success() {
-            return Err(format!("Unable to run pwd command in snapshot dir {:?}", stat).into());
-        }
-        Ok(())
-    }
-
-    fn full_sure(&self, dir: &str, name: &str) -> Result<()> {
-        println!("  % sure -f {} ({})", name, dir);
-        if !self.back.dry_run {
-            rsure::update(dir, rsure::no_path(), name)?;
-        }
-        Ok(())
-    }
-
-    fn incremental_sure(&self, dir: &str, old_name: &str, name: &str) -> Result<()> {
-        println!("  % sure --old {} -f {} ({})", old_name, name, dir);
-        if !self.back.dry_run {
-            rsure::update(dir, Some(old_name), name)?;
-        }
-        Ok(())
-    }
-
-    fn bksure(&self, bkd: &BkDir, dir: &str, file: &str, old: Option<&str>, name: &str) -> Result<()> {
-        println!("  % sure file={:?} old={:?}, name={:?} (dir={:?})", file, old, name, dir);
-        if !self.back.dry_run {
-            // TODO: Generalize this functionality in rsure's API itself.
-            let mut new_tree = rsure::scan_fs(dir)?;
-
-            // Update the hashes.
-            match old {
-                None => (),
-                Some(src) => {
-                    let old_tree = bkd.load(file, src)?;
-                    new_tree.update_from(&old_tree);
-                },
+            if !steps.is_empty() {
+                jobs.push(Job {
+                    access: access,
+                    ds: ds,
+                    steps: steps,
+                });
             }
-
-            let estimate = new_tree.hash_estimate();
-            let mut progress = Progress::new(estimate.files, estimate.bytes);
-            new_tree.hash_update(Path::new(dir), &mut progress);
-            progress.flush();
-
-            bkd.save(&new_tree, file, name)?;
         }
-        Ok(())
-    }
 
-    fn base(&self) -> &str {
-        &self.back.host.base[..]
-    }
-
-    pub fn prune_snaps(&self) -> Result<()> {
-        let snaps = self.get_snaps(local_path(&self.base()))?;
-        for ds in &snaps {
-            println!("name: {}", ds.name);
-            let mut seen = HashMap::new();
-            let mut prunes = Vec::new();
-            for snap in &ds.snaps {
-                match self.snap_re.captures(snap) {
-                    None => (),
-                    Some(caps) => {
-                        let num = caps.at(1).unwrap().parse::<u32>().unwrap();
-                        seen.insert(num, PruneInfo {
-                            num: num,
-                            name: snap.to_owned(),
-                        });
-
-                        // Prune away entries with the same number of bits.
-                        let mypop = num.count_ones();
-                        for i in 1 .. num {
-                            if i.count_ones() != mypop {
-                                continue
-                            }
-                            match seen.entry(i) {
-                                Entry::Occupied(ent) => {
-                                    prunes.push(ent.remove());
-                                },
-                                Entry::Vacant(_) => (),
-                            }
-                        }
-                    },
+        let bkd = Arc::new(Mutex::new(bkd));
+        let base = base.to_owned();
+        let dry_run = self.back.dry_run;
+        hashing::run_jobs(self.back, "bksure", jobs, threads, move |job, step, meter| {
+            // Unmounted again once hashed, if it was mounted for this.  A dry run mounts
+            // nothing.
+            let mount = if dry_run { None } else { Some(job.mount(&step.snap)?) };
+            let dir = match mount {
+                Some(ref mount) => mount.dir().to_owned(),
+                None => job.location(&step.snap),
+            };
+            let file = format!("{}.dat", &job.ds.name[base.len()+1..]);
+            println!("  % sure file={:?} old={:?}, name={:?} (dir={:?})", file, step.old,
+                     step.dest, dir);
+            if !dry_run {
+                // TODO: Generalize this functionality in rsure's API itself.
+                let mut new_tree = rsure::scan_fs(&dir)?;
+
+                // Update the hashes.
+                if let Some(ref src) = step.old {
+                    let old_tree = bkd.lock().unwrap().load(&file, src)?;
+                    new_tree.update_from(&old_tree);
                 }
+                hashing::hash_tree(&mut new_tree, Path::new(&dir), meter);
+
+                bkd.lock().unwrap().save(&new_tree, &file, &step.dest)?;
             }
+            Ok(())
+        })
+    }
 
-            // Prune the old ones, but make sure to keep some.
-            if prunes.len() > PRUNE_KEEP {
-                for prune in &prunes[..prunes.len() - PRUNE_KEEP] {
-                    let name = format!("{}@{}", ds.name, prune.name);
-                    let mut cmd = Command::new("zfs");
-                    cmd.arg("destroy");
-                    cmd.arg(name);
-                    println!(" % {:?}", cmd);
-                    if !self.back.dry_run {
-                        // TODO: Factor this always run command.
-                        let stat = cmd.status()?;
-                        if !stat.success() {
-                            return Err(format!("Unable to run zfs command: {:?}", stat).into());
-                        }
+    /// How to read the snapshots of each of the datasets, to hash them.  The properties this
+    /// needs are read for all of the datasets at once.
+    fn snap_access(&self, dss: &[DataSet]) -> Vec<Access> {
+        let names: Vec<_> = dss.iter().map(|ds| &ds.name[..]).collect();
+        let dir = local_path(self.base());
+        let mut props = self.get_props_bulk(&*dir, &names,
+                                            &["type", "mounted", "mountpoint", "snapdir"]);
+
+        // zfs without native encryption rejects keystatus, so it is read on its own, and the
+        // keys are taken to be loaded if it can't be.
+        if let Ok(ref mut props) = props {
+            if let Ok(keys) = self.get_props_bulk(&*dir, &names, &["keystatus"]) {
+                for (name, key) in keys {
+                    if let Some(set) = props.get_mut(&name) {
+                        set.extend(key);
                     }
                 }
             }
         }
+        dss.iter().map(|ds| {
+            match props {
+                Ok(ref props) => match props.get(&ds.name) {
+                    Some(set) => set.access(),
+                    None => Access::Unreachable("zfs gave none of its properties".to_owned()),
+                },
+                Err(ref e) => Access::Unreachable(format!("unable to read its properties: {}", e)),
+            }
+        }).collect()
+    }
 
-        return Ok(());
+    fn base(&self) -> &str {
+        &self.root.base[..]
     }
 
     /// Clone the snapshots in 'src' to 'dest', going through each volume.
-    pub fn clone_snaps(&self, src: Rc<ZfsPath>, dest: Rc<ZfsPath>) -> Result<()> {
+    pub fn clone_snaps(&self, src: Arc<ZfsPath>, dest: Arc<ZfsPath>,
+                       opts: &CloneOptions) -> Result<()> {
         let state = CloneState {
             zfs: self,
             src: src,
             dest: dest,
+            opts: opts.clone(),
         };
         state.clone_snaps()
     }
 
+    /// Replicate this root to a target from the config file.  The root
+    /// is cloned to a dataset named after it, under the target's
+    /// destination.
+    pub fn replicate(&self, target: &Target) -> Result<()> {
+        let dest = self.root.dest_under(&target.dest);
+        println!("Replicate {} to {} ({})", self.base(), dest, target.name());
+        let dest = ZfsPath::parse_ssh(&dest, target.ssh_options.as_ref().map_or(&[], |x| &x[..]));
+        self.clone_snaps(local_path(self.base()), dest, &CloneOptions::from_target(target))
+    }
+
+}
+
+/// Options that control how `clone_snaps` replicates.
+#[derive(Clone, Debug, Default)]
+pub struct CloneOptions {
+    /// Do an initial full send of source datasets that aren't present on
+    /// the destination, rather than skipping them.
+    pub fresh: bool,
+    /// Flags given to `zfs send`, instead of the default "-Le".
+    pub send_flags: Option<Vec<String>>,
+    /// Properties to set on received datasets, with `zfs recv -o`.
+    pub recv_set: Vec<(String, String)>,
+    /// Properties to not receive, with `zfs recv -x`.
+    pub recv_exclude: Vec<String>,
+    /// If not empty, only clone these datasets (relative to the source),
+    /// and those beneath them.
+    pub include: Vec<String>,
+    /// Don't clone these datasets (relative to the source), or those
+    /// beneath them.
+    pub exclude: Vec<String>,
+}
+
+impl CloneOptions {
+    /// Build the options given for a replication target in the config.
+    pub fn from_target(target: &Target) -> CloneOptions {
+        let recv_set = match target.recv_set {
+            None => vec![],
+            Some(ref props) => props.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        };
+        CloneOptions {
+            fresh: target.fresh.unwrap_or(false),
+            send_flags: target.send_flags.clone(),
+            recv_set: recv_set,
+            recv_exclude: target.recv_exclude.clone().unwrap_or_else(Vec::new),
+            include: target.include.clone().unwrap_or_else(Vec::new),
+            exclude: target.exclude.clone().unwrap_or_else(Vec::new),
+        }
+    }
+
+    /// How much of the dataset with the given name, relative to the source,
+    /// should be cloned?
+    fn wanted(&self, sub: &str) -> Want {
+        let under = |pat: &String| {
+            pat.is_empty() || sub == pat || sub.starts_with(&format!("{}/", pat))
+        };
+        // Strict ancestors of an included dataset.
+        let above = |pat: &String| {
+            !pat.is_empty() && (sub.is_empty() || pat.starts_with(&format!("{}/", sub)))
+        };
+        if self.exclude.iter().any(&under) {
+            Want::Nothing
+        } else if self.include.is_empty() || self.include.iter().any(&under) {
+            Want::All
+        } else if self.include.iter().any(&above) {
+            Want::Parent
+        } else {
+            Want::Nothing
+        }
+    }
+}
+
+/// How much of a source dataset `clone_snaps` replicates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Want {
+    /// All of its snapshots.
+    All,
+    /// None of its snapshots, but it is still created on a fresh
+    /// destination, since datasets beneath it are wanted.
+    Parent,
+    Nothing,
 }
 
 struct CloneState<'b, 'a: 'b> {
-    src: Rc<ZfsPath>,
-    dest: Rc<ZfsPath>,
+    src: Arc<ZfsPath>,
+    dest: Arc<ZfsPath>,
     zfs: &'b ZFS<'a>,
+    opts: CloneOptions,
 }
 
 impl<'a, 'b> CloneState<'a, 'b> {
@@ -454,25 +725,132 @@ impl<'a, 'b> CloneState<'a, 'b> {
 
         // println!("dmap: {:#?}", dmap);
 
+        // Any interrupted receives into the destination datasets, all read at once.
+        let dnames: Vec<_> = dest_snaps.iter().map(|e| &e.name[..]).collect();
+        let dprops = self.zfs.get_props_bulk(&*self.dest, &dnames, &["receive_resume_token"])?;
+
+        // The source listing has parents before their children, so fresh
+        // parents are always created before anything is received into them.
         for ssnap in &src_snaps {
             // println!("Check: {:?}", &ssnap.name[src.len()..]);
-            match dmap.get(&ssnap.name[self.src.name().len()..]) {
-                None => println!("Fresh: {}", ssnap.name),
+            check_stop()?;
+            let sub = &ssnap.name[self.src.name().len()..];
+            let want = self.opts.wanted(sub.trim_left_matches('/'));
+            let errors = self.zfs.back.report.errors();
+            let result = match dmap.get(sub) {
+                _ if want == Want::Nothing => {
+                    println!("Skip: {}", ssnap.name);
+                    continue;
+                }
+                None if want == Want::Parent && self.opts.fresh => {
+                    println!("Fresh: {} (parent only)", ssnap.name);
+
+                    self.create_dest(&format!("{}{}", self.dest.name(), sub))
+                }
+                _ if want == Want::Parent => {
+                    println!("Skip: {} (parent only)", ssnap.name);
+                    continue;
+                }
+                None if self.opts.fresh => {
+                    println!("Fresh: {}", ssnap.name);
+
+                    self.clone_fresh(ssnap)
+                },
+                None => {
+                    println!("Fresh: {} (skipping)", ssnap.name);
+                    Ok(())
+                }
                 Some(dsnap) => {
                     println!("Clone: {}", ssnap.name);
 
-                    self.clone_volume(ssnap, dsnap)?;
+                    let token = dprops.get(&dsnap.name).and_then(|p| p.resume_token());
+                    self.clone_volume(ssnap, dsnap, token)
                 },
+            };
+            if let Err(e) = result {
+                // Failed sends are in the report already, but not failures getting ready for
+                // them.
+                if self.zfs.back.report.errors() == errors {
+                    let entry = Entry::new(&ssnap.name, "send")
+                        .dest(&format!("{}{}", self.dest.name(), sub));
+                    self.zfs.back.report.record(Instant::now(), entry, &Err::<(), _>(&e));
+                }
+                carry_on(self.zfs.back, &ssnap.name, e)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn clone_volume(&self, src: &DataSet, dest: &DataSet, token: Option<&str>) -> Result<()> {
+        // If an earlier receive was interrupted, the destination holds a
+        // resume token.  Finish that stream first, since zfs will refuse
+        // any other receive until the partial state is completed.
+        let mut dsnaps = None;
+        if let Some(token) = token {
+            println!("  resume {:?} to {:?}", src.name, dest.name);
+            self.run_clone(src, dest, &SendStream::Resume(token))?;
+
+            // The resumed stream only carries a single snapshot, so
+            // re-read what the destination has now.
+            if !self.zfs.back.dry_run {
+                dsnaps = Some(self.dest_snaps(dest)?);
             }
         }
+        let dsnaps = dsnaps.as_ref().unwrap_or(&dest.snaps);
+        self.clone_increments(src, dest, dsnaps)
+    }
+
+    /// Replicate a source dataset that isn't present on the destination at
+    /// all.  The oldest snapshot is sent in full, creating the destination
+    /// dataset, and the rest follow incrementally.
+    fn clone_fresh(&self, src: &DataSet) -> Result<()> {
+        let name = format!("{}{}", self.dest.name(), &src.name[self.src.name().len()..]);
+
+        let first = match src.snaps.first() {
+            Some(first) => first,
+            None => {
+                // Nothing to send, but the dataset may still be needed as
+                // the parent of others.
+                return self.create_dest(&name);
+            }
+        };
+
+        let dest = DataSet {
+            dir: self.dest.clone(),
+            name: name,
+            snaps: vec![],
+            mount: "-".to_owned(),
+        };
+
+        println!("  full {:?} {:?} to {:?}", src.name, first, dest.name);
+        self.run_clone(src, &dest, &SendStream::Snaps { old: None, new: first })?;
+
+        self.clone_increments(src, &dest, &src.snaps[..1])
+    }
 
+    /// Create an empty dataset on the destination.
+    fn create_dest(&self, name: &str) -> Result<()> {
+        let mut cmd = self.dest.command();
+        cmd.args(&["create", "-p", name]);
+        if self.zfs.back.dry_run {
+            println!("Would run: {:?}", cmd);
+        } else {
+            println!("Run: {:?}", cmd);
+            let stat = cmd.status()?;
+            if !stat.success() {
+                return Err(format!("Unable to run zfs command: {:?}", stat).into());
+            }
+        }
         Ok(())
     }
 
-    fn clone_volume(&self, src: &DataSet, dest: &DataSet) -> Result<()> {
+    /// Send the snapshots of `src` newer than the latest one already in
+    /// `dsnaps`, the snapshots present on `dest`.
+    fn clone_increments(&self, src: &DataSet, dest: &DataSet, dsnaps: &[String]) -> Result<()> {
         // Scan for the most recent index in the src snapshots that is
         // present in the dests, and backup the rest.
-        let dpresent = dest.snaps.iter().collect::<HashSet<_>>();
+        let dpresent = dsnaps.iter().collect::<HashSet<_>>();
         let mut latest = None;
         for (i, sname) in src.snaps.iter().enumerate() {
             if dpresent.contains(sname) {
@@ -489,11 +867,10 @@ impl<'a, 'b> CloneState<'a, 'b> {
                 // as long as we're doing the backups.
                 println!("Warning: snapshot is already present: {:?}", name);
             } else {
+                check_stop()?;
                 let old_name = last.map(|x| &src.snaps[x][..]);
                 println!("  clone {:?} {:?} to {:?} {:?}", src.name, old_name, dest.name, name);
-                let size = self.estimate_size(src, old_name, name)?;
-                println!("    size: {:?}", size);
-                self.run_clone(src, dest, old_name, name, size)?;
+                self.run_clone(src, dest, &SendStream::Snaps { old: old_name, new: name })?;
             }
 
             last = Some(snum);
@@ -506,17 +883,39 @@ impl<'a, 'b> CloneState<'a, 'b> {
         Ok(())
     }
 
-    fn estimate_size(&self, dset: &DataSet, old_name: Option<&str>, new_name: &str) -> Result<u64> {
-        let mut cmd = self.src.command();
-        cmd.args(&["send", "-nP", "-Le"]);
-        match old_name {
-            None => (),
-            Some(name) => {
-                cmd.args(&["-I", &format!("@{}", name)]);
-            },
+    /// Query the snapshots currently present on a single destination
+    /// dataset.
+    fn dest_snaps(&self, dest: &DataSet) -> Result<Vec<String>> {
+        let mut cmd = self.dest.command();
+        cmd.args(&["list", "-H", "-t", "snapshot", "-o", "name", "-d", "1", &dest.name]);
+        let out = cmd.output()?;
+        if !out.status.success() {
+            return Err(format!("zfs list returned error: {:?}", out.status).into());
+        }
+
+        let mut result = vec![];
+        for line in BufReader::new(&out.stdout[..]).lines() {
+            let line = line?;
+            match line.splitn(2, '@').nth(1) {
+                Some(snap) => result.push(snap.to_owned()),
+                None => return Err(format!("zfs list gave non-snapshot: {:?}", line).into()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// The flags to give `zfs send` for new streams.
+    fn send_flags(&self) -> Vec<String> {
+        match self.opts.send_flags {
+            Some(ref flags) => flags.clone(),
+            None => vec!["-Le".to_owned()],
         }
-        let new_arg = format!("{}@{}", dset.name, new_name);
-        cmd.arg(&new_arg);
+    }
+
+    fn estimate_size(&self, dset: &DataSet, stream: &SendStream) -> Result<u64> {
+        let mut cmd = self.src.command();
+        cmd.args(&["send", "-nP"]);
+        stream.add_args(&mut cmd, dset, &self.send_flags());
         let out = cmd.output()?;
         if !out.status.success() {
             return Err(format!("zfs send returned error: {:?}", out.status).into());
@@ -533,50 +932,71 @@ impl<'a, 'b> CloneState<'a, 'b> {
         }
     }
 
-    fn run_clone(&self, src: &DataSet, dest: &DataSet,
-                 old_name: Option<&str>, new_name: &str, est_size: u64) -> Result<()> {
-        // TODO: A lot is common with `estimate_size`, factor that code
-        // out.
-        let mut cmd1 = self.src.command();
-        cmd1.args(&["send", "-Le"]);
-        match old_name {
-            None => (),
-            Some(name) => {
-                cmd1.args(&["-I", &format!("@{}", name)]);
-            },
+    /// Estimate the size of a stream, and send it, recording it in the report.
+    fn run_clone(&self, src: &DataSet, dest: &DataSet, stream: &SendStream) -> Result<()> {
+        let entry = Entry::new(&src.name, stream.action())
+            .snapshots(&stream.snapshots())
+            .dest(&dest.name);
+        let dry_run = self.zfs.back.dry_run;
+
+        let start = Instant::now();
+        let result = self.estimate_size(src, stream).and_then(|size| {
+            println!("    size: {:?}", size);
+            if dry_run {
+                println!("ZFS clone: {:?} to {:?}", stream, dest.name);
+                Ok(0)
+            } else {
+                self.send_stream(src, dest, stream, size)
+            }
+        });
+        if dry_run {
+            self.zfs.back.report.record(start, entry, &result);
+        } else {
+            self.zfs.back.report.record_bytes(start, entry, &result);
         }
-        let new_arg = format!("{}@{}", src.name, new_name);
-        cmd1.arg(&new_arg);
+        result?;
+        Ok(())
+    }
+
+    /// Run a send of `stream` into a receive on the destination, returning
+    /// the number of bytes sent.
+    fn send_stream(&self, src: &DataSet, dest: &DataSet,
+                   stream: &SendStream, est_size: u64) -> Result<u64> {
+
+        let mut cmd1 = self.src.command();
+        cmd1.arg("send");
+        stream.add_args(&mut cmd1, src, &self.send_flags());
         cmd1.stdout(Stdio::piped());
+        // Leave the daemon to decide when to stop, between streams.
+        own_process_group(&mut cmd1);
         let mut child1 = cmd1.spawn()?;
 
-        if self.zfs.back.dry_run {
-            println!("ZFS clone: {:?} to {:?}@{:?}", old_name, src.name, new_name);
-            return Ok(())
+        // Receive with '-s' so that an interrupted stream leaves a resume
+        // token on the destination instead of discarding the partial data.
+        let mut cmd2 = self.dest.command();
+        cmd2.args(&["recv", "-s", "-vF"]);
+        for &(ref name, ref value) in &self.opts.recv_set {
+            cmd2.args(&["-o", &format!("{}={}", name, value)]);
         }
-
-        // Use the 'pv' program as a progress monitor.
-        let mut cmd2 = Command::new("pv");
-        let size_arg = format!("{}", est_size);
-        cmd2.args(&["-s", &size_arg]);
-        unsafe {
-            let fd = child1.stdout.as_ref().unwrap().as_raw_fd();
-            cmd2.stdin(Stdio::from_raw_fd(fd));
+        for name in &self.opts.recv_exclude {
+            cmd2.args(&["-x", name]);
         }
-        cmd2.stdout(Stdio::piped());
+        cmd2.arg(&dest.name);
+        cmd2.stdin(Stdio::piped());
+        cmd2.stdout(Stdio::inherit());
         cmd2.stderr(Stdio::inherit());
+        own_process_group(&mut cmd2);
         let mut child2 = cmd2.spawn()?;
 
-        // Pipe this into zfs recv.
-        let mut cmd3 = self.dest.command();
-        cmd3.args(&["recv", "-vF", &dest.name]);
-        unsafe {
-            let fd = child2.stdout.as_ref().unwrap().as_raw_fd();
-            cmd3.stdin(Stdio::from_raw_fd(fd));
-        }
-        cmd3.stdout(Stdio::inherit());
-        cmd3.stderr(Stdio::inherit());
-        let mut child3 = cmd3.spawn()?;
+        // Relay the stream ourselves, to show progress.  Both pipes are
+        // closed at the end of this block, so that the receive sees the
+        // end of the stream (or the send sees the receive go away).
+        let copied = {
+            let mut input = child1.stdout.take().unwrap();
+            let mut output = child2.stdin.take().unwrap();
+            let mut relay = Relay::new(&stream.label(src), est_size);
+            relay.copy(&mut input, &mut output)
+        };
 
         match child1.wait()? {
             status if status.success() => (),
@@ -586,44 +1006,85 @@ impl<'a, 'b> CloneState<'a, 'b> {
         }
 
         match child2.wait()? {
-            status if status.success() => (),
-            status => {
-                return Err(format!("Error running pv: {:?}", status).into());
-            }
-        }
-
-        match child3.wait()? {
             status if status.success() => (),
             status => {
                 return Err(format!("Error running zfs recv: {:?}", status).into());
             }
         }
 
-        Ok(())
+        Ok(copied?)
     }
 }
 
+/// The stream a single `zfs send` should produce.
 #[derive(Debug)]
-struct PruneInfo {
-    num: u32,
-    name: String,
+enum SendStream<'s> {
+    /// Send `new`, incrementally from `old` if given, otherwise in full.
+    Snaps { old: Option<&'s str>, new: &'s str },
+    /// Resume an interrupted send, using the token left on the destination.
+    Resume(&'s str),
+}
+
+impl<'s> SendStream<'s> {
+    /// Add the arguments describing this stream to a `zfs send` command.
+    /// The flags only apply to new streams, since a resumed stream keeps
+    /// the flags it was started with.
+    fn add_args(&self, cmd: &mut Command, dset: &DataSet, flags: &[String]) {
+        match *self {
+            SendStream::Snaps { old, new } => {
+                cmd.args(flags);
+                if let Some(name) = old {
+                    cmd.args(&["-I", &format!("@{}", name)]);
+                }
+                cmd.arg(format!("{}@{}", dset.name, new));
+            }
+            SendStream::Resume(token) => {
+                cmd.args(&["-t", token]);
+            }
+        }
+    }
+
+    /// The action, as recorded in the report.
+    fn action(&self) -> &'static str {
+        match *self {
+            SendStream::Snaps { .. } => "send",
+            SendStream::Resume(_) => "resume",
+        }
+    }
+
+    /// The snapshots sent, as far as is known.  A resumed stream doesn't
+    /// say which snapshot it carries.
+    fn snapshots(&self) -> Vec<&'s str> {
+        match *self {
+            SendStream::Snaps { new, .. } => vec![new],
+            SendStream::Resume(_) => vec![],
+        }
+    }
+
+    /// A short description of this stream, for progress messages.
+    fn label(&self, dset: &DataSet) -> String {
+        match *self {
+            SendStream::Snaps { new, .. } => format!("{}@{}", dset.name, new),
+            SendStream::Resume(_) => format!("{} (resumed)", dset.name),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct DataSet {
-    dir: Rc<ZfsPath>,
+    dir: Arc<ZfsPath>,
     name: String,
     snaps: Vec<String>,
     mount: String,
 }
 
 struct SnapBuilder {
-    dir: Rc<ZfsPath>,
+    dir: Arc<ZfsPath>,
     work: Vec<DataSet>,
 }
 
 impl SnapBuilder {
-    fn new(dir: Rc<ZfsPath>) -> SnapBuilder {
+    fn new(dir: Arc<ZfsPath>) -> SnapBuilder {
         SnapBuilder {
             dir: dir,
             work: vec![],
@@ -661,19 +1122,45 @@ mod test {
     use super::*;
     use config;
     use RBack;
+    use report::Report;
 
     #[test]
     fn test_snaps() {
         let back = RBack {
-            host: config::Host {
-                host: "test-host".to_owned(),
-                base: "arch/arch".to_owned(),
-                snap_prefix: "aa2015-".to_owned(),
-            },
+            host: config::Host::for_base("arch/arch", "aa2015-"),
             dry_run: false,
+            fail_fast: false,
+            report: Report::new(),
         };
-        let zfs = ZFS::new(&back);
+        let roots = back.host.roots().unwrap();
+        let zfs = ZFS::new(&back, &roots[0]).unwrap();
         let snaps = zfs.get_snaps(local_path("a64/arch")).unwrap();
         println!("next: {}", zfs.next_snap(&snaps));
     }
+
+    #[test]
+    fn test_wanted() {
+        let mut opts = CloneOptions::default();
+        assert_eq!(opts.wanted(""), Want::All);
+        assert_eq!(opts.wanted("home/user"), Want::All);
+
+        opts.include = vec!["home".to_owned()];
+        opts.exclude = vec!["home/scratch".to_owned()];
+        assert_eq!(opts.wanted(""), Want::Parent);
+        assert_eq!(opts.wanted("home"), Want::All);
+        assert_eq!(opts.wanted("home/user"), Want::All);
+        assert_eq!(opts.wanted("homer"), Want::Nothing);
+        assert_eq!(opts.wanted("home/scratch"), Want::Nothing);
+        assert_eq!(opts.wanted("home/scratch/tmp"), Want::Nothing);
+
+        // Parents are created for the included datasets to be received into.
+        opts.include = vec!["home/user".to_owned()];
+        opts.exclude = vec![];
+        assert_eq!(opts.wanted(""), Want::Parent);
+        assert_eq!(opts.wanted("home"), Want::Parent);
+        assert_eq!(opts.wanted("home/user"), Want::All);
+        assert_eq!(opts.wanted("home/user/mail"), Want::All);
+        assert_eq!(opts.wanted("home/other"), Want::Nothing);
+        assert_eq!(opts.wanted("hom"), Want::Nothing);
+    }
 }
@@ -0,0 +1,232 @@
+//! Snapshot names.
+//!
+//! Snapshot names are built from a template in the host config, such as
+//! "{prefix}{seq}-{year}{month}{day}-{hour}{minute}".  The template is also turned into a regex,
+//! so that the sequence number, and the time when the template has one, can be read back out of
+//! existing snapshot names.  Names in the original `<prefix><num>-<MM>-<DD>` form are always
+//! recognized, so that numbering continues across a change of template.
+
+use chrono::{Datelike, Local, TimeZone, Timelike, UTC};
+use regex::{self, Regex};
+use super::Result;
+
+/// The template matching the names rback has always used.
+pub const DEFAULT_TEMPLATE: &'static str = "{prefix}{seq}-{month}-{day}";
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    Prefix,
+    Seq,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    /// Times are in UTC, rather than local time.  Shows as a 'Z'.
+    Utc,
+}
+
+/// What can be learned from an existing snapshot name.
+#[derive(Debug, PartialEq)]
+pub struct SnapName {
+    pub num: u32,
+    /// The time the name was generated, in seconds since the epoch, if the name has a full date.
+    pub time: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct Naming {
+    prefix: String,
+    tokens: Vec<Token>,
+    utc: bool,
+    re: Regex,
+    legacy_re: Regex,
+}
+
+impl Naming {
+    /// Build the naming for the given prefix and template.  `None` uses the default template.
+    pub fn new(prefix: &str, template: Option<&str>) -> Result<Naming> {
+        let template = template.unwrap_or(DEFAULT_TEMPLATE);
+        let tokens = parse_template(template)?;
+        if !tokens.contains(&Token::Seq) {
+            return Err(format!("Snapshot template {:?} has no {{seq}}", template).into());
+        }
+
+        let mut pat = "^".to_owned();
+        for tok in &tokens {
+            match *tok {
+                Token::Literal(ref text) => pat.push_str(&regex::quote(text)),
+                Token::Prefix => pat.push_str(&regex::quote(prefix)),
+                Token::Seq => pat.push_str(r"(?P<seq>\d+)"),
+                Token::Year => pat.push_str(r"(?P<year>\d{4})"),
+                Token::Month => pat.push_str(r"(?P<month>\d{2})"),
+                Token::Day => pat.push_str(r"(?P<day>\d{2})"),
+                Token::Hour => pat.push_str(r"(?P<hour>\d{2})"),
+                Token::Minute => pat.push_str(r"(?P<minute>\d{2})"),
+                Token::Utc => pat.push_str("Z"),
+            }
+        }
+        pat.push('$');
+
+        let legacy = format!("^{}(\\d+)[-\\.]([-\\.\\d]+)$", regex::quote(prefix));
+
+        Ok(Naming {
+            prefix: prefix.to_owned(),
+            utc: tokens.contains(&Token::Utc),
+            tokens: tokens,
+            re: Regex::new(&pat).unwrap(),
+            legacy_re: Regex::new(&legacy).unwrap(),
+        })
+    }
+
+    /// Generate the name for snapshot number `num`, taken now.
+    pub fn format(&self, num: u32) -> String {
+        self.format_at(num, UTC::now().timestamp())
+    }
+
+    /// Generate the name for snapshot number `num`, taken at the given time (in seconds since the
+    /// epoch).
+    pub fn format_at(&self, num: u32, time: i64) -> String {
+        if self.utc {
+            self.format_with(num, UTC.timestamp(time, 0))
+        } else {
+            self.format_with(num, Local.timestamp(time, 0))
+        }
+    }
+
+    fn format_with<T: Datelike + Timelike>(&self, num: u32, t: T) -> String {
+        let mut result = String::new();
+        for tok in &self.tokens {
+            let text = match *tok {
+                Token::Literal(ref text) => text.clone(),
+                Token::Prefix => self.prefix.clone(),
+                Token::Seq => format!("{:05}", num),
+                Token::Year => format!("{:04}", t.year()),
+                Token::Month => format!("{:02}", t.month()),
+                Token::Day => format!("{:02}", t.day()),
+                Token::Hour => format!("{:02}", t.hour()),
+                Token::Minute => format!("{:02}", t.minute()),
+                Token::Utc => "Z".to_owned(),
+            };
+            result.push_str(&text);
+        }
+        result
+    }
+
+    /// Decode a snapshot name.  Returns None if this isn't one of our snapshots.
+    pub fn parse(&self, name: &str) -> Option<SnapName> {
+        if let Some(caps) = self.re.captures(name) {
+            let num = match caps.name("seq").unwrap().parse::<u32>() {
+                Ok(num) => num,
+                Err(_) => return None,
+            };
+            let field = |n| caps.name(n).and_then(|v| v.parse::<u32>().ok());
+            let time = match (field("year"), field("month"), field("day")) {
+                (Some(year), Some(month), Some(day)) => {
+                    let hour = field("hour").unwrap_or(0);
+                    let minute = field("minute").unwrap_or(0);
+                    self.timestamp(year as i32, month, day, hour, minute)
+                }
+                _ => None,
+            };
+            return Some(SnapName {
+                num: num,
+                time: time,
+            });
+        }
+
+        match self.legacy_re.captures(name) {
+            None => None,
+            Some(caps) => caps.at(1).unwrap().parse::<u32>().ok().map(|num| {
+                SnapName {
+                    num: num,
+                    time: None,
+                }
+            }),
+        }
+    }
+
+    fn timestamp(&self, year: i32, month: u32, day: u32, hour: u32, minute: u32) -> Option<i64> {
+        if self.utc {
+            UTC.ymd_opt(year, month, day).and_hms_opt(hour, minute, 0)
+                .earliest().map(|t| t.timestamp())
+        } else {
+            Local.ymd_opt(year, month, day).and_hms_opt(hour, minute, 0)
+                .earliest().map(|t| t.timestamp())
+        }
+    }
+}
+
+/// Split a template into its tokens.
+fn parse_template(template: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = template;
+    while !rest.is_empty() {
+        match rest.find('{') {
+            None => {
+                tokens.push(Token::Literal(rest.to_owned()));
+                break;
+            }
+            Some(0) => {
+                let end = match rest.find('}') {
+                    Some(end) => end,
+                    None => return Err(format!("Unterminated token in template {:?}", template).into()),
+                };
+                tokens.push(match &rest[1..end] {
+                    "prefix" => Token::Prefix,
+                    "seq" => Token::Seq,
+                    "year" => Token::Year,
+                    "month" => Token::Month,
+                    "day" => Token::Day,
+                    "hour" => Token::Hour,
+                    "minute" => Token::Minute,
+                    "utc" => Token::Utc,
+                    other => return Err(format!("Unknown token {{{}}} in template {:?}",
+                                                other, template).into()),
+                });
+                rest = &rest[end + 1..];
+            }
+            Some(pos) => {
+                tokens.push(Token::Literal(rest[..pos].to_owned()));
+                rest = &rest[pos..];
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let n = Naming::new("aa2015-", None).unwrap();
+        let name = n.format(42);
+        assert!(name.starts_with("aa2015-00042-"));
+        assert_eq!(n.parse(&name), Some(SnapName { num: 42, time: None }));
+        assert_eq!(n.parse("other-00042-10-16"), None);
+    }
+
+    #[test]
+    fn test_template() {
+        let n = Naming::new("hourly-", Some("{prefix}{seq}-{year}{month}{day}-{hour}{minute}{utc}"))
+            .unwrap();
+        // 2026-10-16 14:30 UTC
+        let time = 1792161000;
+        let name = n.format_at(7, time);
+        assert_eq!(name, "hourly-00007-20261016-1430Z");
+        assert_eq!(n.parse(&name), Some(SnapName { num: 7, time: Some(time) }));
+
+        // Names from before the template still count.
+        assert_eq!(n.parse("hourly-00003-10-15"), Some(SnapName { num: 3, time: None }));
+    }
+
+    #[test]
+    fn test_bad_template() {
+        assert!(Naming::new("a", Some("{prefix}{year}")).is_err());
+        assert!(Naming::new("a", Some("{prefix}{seq}{bogus}")).is_err());
+        assert!(Naming::new("a", Some("{prefix}{seq")).is_err());
+    }
+}
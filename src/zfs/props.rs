@@ -1,57 +1,198 @@
 //! Manage ZFS properties
 //!
 //! Parse and read the output of 'zfs get' to be able to interpret those that are meaningful.
+//!
+//! The values are kept as the text zfs gives (with `-p`, so sizes are in bytes, and times in
+//! seconds since the epoch), and are parsed into the type asked for when they are read, with
+//! `PropSet::get`, or one of the accessors for the properties rback uses.
 
+use std::collections::HashMap;
+use std::fmt;
 use std::io::prelude::*;
 use std::io::BufReader;
-use super::{local_path, DataSet, Result, ZFS};
+use std::process::Command;
+use std::slice;
+use super::relay::humanize;
+use super::{local_path, Result, ZFS, ZfsPath};
+
+/// The most names to give a single "zfs get", so that the command line stays well within the
+/// limit of the system, even with tens of thousands of snapshots.
+const BULK_NAMES: usize = 500;
+
+/// The kinds of source, as `Source::kind` gives them.
+const SOURCE_KINDS: &'static [&'static str] = &["local", "default", "inherited", "received",
+                                                "temporary", "none"];
+
+/// The properties that are sizes, in bytes.
+const SIZE_PROPS: &'static [&'static str] = &[
+    "used", "available", "referenced", "logicalused", "logicalreferenced", "quota", "refquota",
+    "reservation", "refreservation", "volsize", "volblocksize", "recordsize", "usedbysnapshots",
+    "usedbydataset", "usedbychildren", "usedbyrefreservation", "written",
+];
 
 impl<'a> ZFS<'a> {
-    /// Read the ZFS properties for the given `DataSet`.  This runs the "zfs get" command, and
-    /// parses the output.
-    pub fn get_props(&self, ds: &DataSet, snap: Option<&str>) -> Result<PropSet> {
-        let mut cmd = ds.dir.command();
-
-        let dname = snap.map_or_else(|| ds.name.to_owned(),
-            |v| format!("{}@{}", ds.name, v));
-        println!("get: {:?}", dname);
-        cmd.args(&["get", "-Hp", "all", &dname]);
+    /// Read properties of many datasets and snapshots, given by their full names, with a "zfs
+    /// get" for each `BULK_NAMES` of them.  Only the properties in `props` are read, or all of
+    /// them if it is empty.  The result is indexed by the name of the dataset or snapshot.
+    pub fn get_props_bulk<S: AsRef<str>>(&self, dir: &ZfsPath, names: &[S], props: &[&str])
+                                         -> Result<HashMap<String, PropSet>> {
+        let props = if props.is_empty() { "all".to_owned() } else { props.join(",") };
+        let mut result = HashMap::new();
+        // Without any names, zfs would give the properties of everything in every pool, so
+        // there is never an empty chunk.
+        for chunk in names.chunks(BULK_NAMES) {
+            let mut cmd = dir.command();
+            cmd.args(&["get", "-Hp", &props]);
+            for name in chunk {
+                cmd.arg(name.as_ref());
+            }
+            let out = cmd.output()?;
+            if !out.status.success() {
+                return Err(format!("zfs get returned error: {:?}", out.status).into());
+            }
+            result.extend(parse_bulk(&out.stdout)?);
+        }
+        Ok(result)
+    }
+
+    /// Read the properties of the pool holding the root.  `zpool get` has the same output as
+    /// `zfs get`.
+    pub fn get_pool_props(&self) -> Result<PropSet> {
+        let mut cmd = Command::new("zpool");
+        cmd.args(&["get", "-Hp", "all", self.root.pool()]);
         let out = cmd.output()?;
         if !out.status.success() {
-            return Err(format!("zfs get returned error: {:?}", out.status).into());
+            return Err(format!("zpool get returned error: {:?}", out.status).into());
+        }
+        parse_props(&out.stdout)
+    }
+
+    /// The properties of the datasets under the base, and of their snapshots too if `snapshots`
+    /// is set, as shown by `rback props`.  `props` are the properties to give, or all of them if
+    /// it is empty, and `sources` the kinds of source to give them from, or any if it is empty.
+    pub fn prop_rows(&self, props: &[&str], snapshots: bool,
+                     sources: &[String]) -> Result<Vec<PropRow>> {
+        let dir = local_path(self.base());
+        let dss = self.get_snaps(dir.clone())?;
+        let mut names = vec![];
+        for ds in &dss {
+            names.push(ds.name.clone());
+            if snapshots {
+                names.extend(ds.snaps.iter().map(|s| format!("{}@{}", ds.name, s)));
+            }
         }
 
-        let mut result = vec![];
+        // All of them are read recursively from the base, rather than by name, since there may
+        // be far too many snapshots to name on a command line.  The names are only used to give
+        // the rows in order.
+        let props = if props.is_empty() { "all".to_owned() } else { props.join(",") };
+        let types = if snapshots { "filesystem,volume,snapshot" } else { "filesystem,volume" };
+        let mut cmd = dir.command();
+        cmd.args(&["get", "-Hp", "-r", "-t", types, &props, self.base()]);
+        let out = cmd.output()?;
+        if !out.status.success() {
+            return Err(format!("zfs get returned error: {:?}", out.status).into());
+        }
+        let found = parse_bulk(&out.stdout)?;
 
-        let buf = out.stdout;
-        for line in BufReader::new(&buf[..]).lines() {
-            let line = line?;
-            let fields: Vec<_> = line.splitn(4, '\t').collect();
-            if fields.len() != 4 {
-                return Err(format!("zfs line doesn't have two fields: {:?}", line).into());
+        let mut rows = vec![];
+        for name in &names {
+            let set = match found.get(name) {
+                Some(set) => set,
+                None => continue,
+            };
+            for prop in set.iter() {
+                if sources.is_empty() || sources.iter().any(|s| s == prop.source.kind()) {
+                    rows.push(PropRow {
+                        name: name.clone(),
+                        property: prop.name.clone(),
+                        value: prop.value.clone(),
+                        source: prop.source.to_string(),
+                    });
+                }
             }
-            result.push(Prop::new(fields[1], fields[2], fields[3]));
         }
-        Ok(PropSet {
-            props: result,
-        })
+        Ok(rows)
     }
+}
 
-    /// Debugging entry point, show the props for the specified subvolumes.
-    pub fn show_props(&self) -> Result<()> {
-        let dss = self.get_snaps(local_path(&self.base()))?;
-        println!("There are {} datasets", dss.len());
-        for ds in &dss {
-            // Get the parent properties.
-            println!("Props for {:?}", ds.name);
-            // let ps = self.get_props(ds, ds.snaps.first().map(|v| v.as_str()))?;
-            let ps = self.get_props(ds, None)?;
-            println!("  mounted   : {:?}", ps.is_mounted());
-            println!("  mountpoint: {:?}", ps.mountpoint());
-            // println!("  {:?}", self.get_props(ds)?);
+/// A single property of a dataset or snapshot, as shown by `rback props`.
+#[derive(Debug, RustcEncodable)]
+pub struct PropRow {
+    pub name: String,
+    pub property: String,
+    /// The value, as zfs gives it, with sizes in bytes.
+    pub value: String,
+    pub source: String,
+}
+
+/// Show the properties as a table, with the sizes made readable.
+pub fn show_props(rows: &[PropRow]) {
+    let values: Vec<_> = rows.iter().map(|r| format_value(&r.property, &r.value)).collect();
+    let name_width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let prop_width = rows.iter().map(|r| r.property.len()).max().unwrap_or(0);
+    let value_width = values.iter().map(|v| v.len()).max().unwrap_or(0);
+    println!("{:<nw$}  {:<pw$}  {:<vw$}  {}", "name", "property", "value", "source",
+             nw = name_width, pw = prop_width, vw = value_width);
+    for (row, value) in rows.iter().zip(&values) {
+        println!("{:<nw$}  {:<pw$}  {:<vw$}  {}", row.name, row.property, value, row.source,
+                 nw = name_width, pw = prop_width, vw = value_width);
+    }
+}
+
+/// The value of a property as it is shown, with sizes made readable.
+fn format_value(property: &str, value: &str) -> String {
+    match value.parse::<u64>() {
+        Ok(size) if SIZE_PROPS.contains(&property) => humanize(size),
+        _ => value.to_owned(),
+    }
+}
+
+/// Parse a comma separated list of the kinds of source, as given to `rback props --source`.
+pub fn source_kinds(text: &str) -> Result<Vec<String>> {
+    let mut result = vec![];
+    for kind in text.split(',') {
+        if !SOURCE_KINDS.contains(&kind) {
+            return Err(format!("Unknown property source {:?}, expected one of {}", kind,
+                               SOURCE_KINDS.join(", ")).into());
         }
-        Ok(())
+        result.push(kind.to_owned());
+    }
+    Ok(result)
+}
+
+/// Parse the output of "zfs get -Hp" (or "zpool get -Hp").
+fn parse_props(buf: &[u8]) -> Result<PropSet> {
+    let mut result = vec![];
+    for line in BufReader::new(buf).lines() {
+        let line = line?;
+        result.push(parse_line(&line)?.1);
+    }
+    Ok(PropSet {
+        props: result,
+    })
+}
+
+/// Parse the output of "zfs get -Hp" for many datasets, into the properties of each.
+fn parse_bulk(buf: &[u8]) -> Result<HashMap<String, PropSet>> {
+    let mut result = HashMap::new();
+    for line in BufReader::new(buf).lines() {
+        let line = line?;
+        let (name, prop) = parse_line(&line)?;
+        result.entry(name.to_owned())
+            .or_insert_with(|| PropSet { props: vec![] })
+            .props.push(prop);
     }
+    Ok(result)
+}
+
+/// Parse a line of "zfs get -H" output, giving the name of the dataset and the property.
+fn parse_line(line: &str) -> Result<(&str, Prop)> {
+    let fields: Vec<_> = line.splitn(4, '\t').collect();
+    if fields.len() != 4 {
+        return Err(format!("zfs line doesn't have four fields: {:?}", line).into());
+    }
+    Ok((fields[0], Prop::new(fields[1], fields[2], fields[3])))
 }
 
 /// A property set holds a set of properties, and has convenient ways of searching for specific
@@ -62,9 +203,30 @@ pub struct PropSet {
 }
 
 impl PropSet {
+    /// Read a property as the given type.  None means the property wasn't present, or didn't
+    /// have a value of that type, such as the "-" of properties that don't apply.
+    pub fn get<T: PropValue>(&self, name: &str) -> Option<T> {
+        self.value(name).and_then(T::parse)
+    }
+
+    /// The properties, in the order zfs gave them.
+    pub fn iter(&self) -> slice::Iter<Prop> {
+        self.props.iter()
+    }
+
+    /// Add the properties of `other`, read separately for the same dataset.
+    pub fn extend(&mut self, other: PropSet) {
+        self.props.extend(other.props);
+    }
+
+    /// Where the value of a property came from.
+    pub fn source(&self, name: &str) -> Option<&Source> {
+        self.scan_name(name).map(|x| &x.source)
+    }
+
     /// Determine if this filesystem is mounted.  None means the property wasn't present.
     pub fn is_mounted(&self) -> Option<bool> {
-        self.scan_name("mounted").and_then(|x| PropSet::from_yesno(&x.value))
+        self.get("mounted")
     }
 
     /// Return the mount point of this filesystem.
@@ -72,6 +234,59 @@ impl PropSet {
         self.scan_name("mountpoint").map(|x| x.value.as_str())
     }
 
+    /// Decide how the files of the snapshots of this dataset can be read.
+    pub fn access(&self) -> Access {
+        if self.value("type") == Some("volume") {
+            return Access::Volume;
+        }
+        if self.value("keystatus") == Some("unavailable") {
+            return Access::Unreachable("its encryption key is not loaded".to_owned());
+        }
+        let snapdir = self.value("snapdir") != Some("disabled");
+        match (self.is_mounted(), self.mountpoint()) {
+            (Some(true), Some(mp)) if mp.starts_with('/') && snapdir => {
+                Access::Snapdir(mp.to_owned())
+            }
+            // Unmounted, with mountpoint=none or legacy, or without a usable .zfs directory.
+            (Some(_), _) => Access::Mount,
+            (None, _) => Access::Unreachable("zfs didn't say whether it is mounted".to_owned()),
+        }
+    }
+
+    /// Return the token needed to resume an interrupted receive into this filesystem.  None means
+    /// there is no partial receive state.
+    pub fn resume_token(&self) -> Option<&str> {
+        match self.scan_name("receive_resume_token") {
+            Some(p) if p.value != "-" && !p.value.is_empty() => Some(p.value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// When the dataset or snapshot was created, in seconds since the epoch.
+    pub fn creation(&self) -> Option<i64> {
+        self.get("creation")
+    }
+
+    /// The space used by the dataset and everything under it, in bytes.  For a snapshot, this is
+    /// the space that destroying it alone would free.
+    pub fn used(&self) -> Option<u64> {
+        self.get("used")
+    }
+
+    /// The space used by the snapshots of the dataset, in bytes.
+    pub fn used_by_snapshots(&self) -> Option<u64> {
+        self.get("usedbysnapshots")
+    }
+
+    /// Return the health of a pool, such as "ONLINE" or "DEGRADED".
+    pub fn health(&self) -> Option<&str> {
+        self.scan_name("health").map(|x| x.value.as_str())
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.scan_name(name).map(|x| x.value.as_str())
+    }
+
     /// Scan for a property of the given name, and return it if found.
     fn scan_name(&self, name: &str) -> Option<&Prop> {
         for p in &self.props {
@@ -81,34 +296,214 @@ impl PropSet {
         }
         None
     }
+}
+
+/// A type that the value of a property can be read as.
+pub trait PropValue: Sized {
+    /// Parse the value, as given by "zfs get -p".
+    fn parse(text: &str) -> Option<Self>;
+}
+
+/// Sizes, in bytes, and counts.
+impl PropValue for u64 {
+    fn parse(text: &str) -> Option<u64> {
+        text.parse().ok()
+    }
+}
+
+/// Times, in seconds since the epoch.
+impl PropValue for i64 {
+    fn parse(text: &str) -> Option<i64> {
+        text.parse().ok()
+    }
+}
+
+/// Ratios, such as "1.50x".
+impl PropValue for f64 {
+    fn parse(text: &str) -> Option<f64> {
+        text.trim_right_matches('x').parse().ok()
+    }
+}
 
-    /// Decode a yes/no response
-    fn from_yesno(text: &str) -> Option<bool> {
+/// Booleans, given as "on" and "off", or "yes" and "no".
+impl PropValue for bool {
+    fn parse(text: &str) -> Option<bool> {
         match text {
-            "yes" => Some(true),
-            "no" => Some(false),
+            "on" | "yes" => Some(true),
+            "off" | "no" => Some(false),
             _ => None,
         }
     }
 }
 
-/// A property has a name, a value, an a source.  For now, let's just store all of these are
-/// strings, and decode them on demand.  We don't store the path for the property, since that can be
-/// inferred for how the property was obtained.  There will be lots of redundant strings here,
-/// because of lots of common values.
+impl PropValue for String {
+    fn parse(text: &str) -> Option<String> {
+        Some(text.to_owned())
+    }
+}
+
+/// Where the value of a property came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Local,
+    Default,
+    /// Inherited from the named dataset.
+    Inherited(String),
+    Received,
+    /// Set by mounting with options, until it is unmounted.
+    Temporary,
+    /// Properties that can't be set, such as "used", have no source.
+    None,
+}
+
+impl Source {
+    /// Parse the source column of "zfs get".
+    pub fn parse(text: &str) -> Source {
+        match text {
+            "local" => Source::Local,
+            "default" => Source::Default,
+            "received" => Source::Received,
+            "temporary" => Source::Temporary,
+            _ if text.starts_with("inherited from ") => {
+                Source::Inherited(text["inherited from ".len()..].to_owned())
+            }
+            _ => Source::None,
+        }
+    }
+
+    /// The kind of source, without where it was inherited from.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Source::Local => "local",
+            Source::Default => "default",
+            Source::Inherited(_) => "inherited",
+            Source::Received => "received",
+            Source::Temporary => "temporary",
+            Source::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Local => write!(f, "local"),
+            Source::Default => write!(f, "default"),
+            Source::Inherited(ref from) => write!(f, "inherited from {}", from),
+            Source::Received => write!(f, "received"),
+            Source::Temporary => write!(f, "temporary"),
+            Source::None => write!(f, "-"),
+        }
+    }
+}
+
+/// How the snapshots of a dataset can be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Through `.zfs/snapshot` under the mount point of the dataset.
+    Snapdir(String),
+    /// By mounting each snapshot on a directory of our own.
+    Mount,
+    /// A volume, which has no files to read.
+    Volume,
+    /// Not at all, for the given reason.
+    Unreachable(String),
+}
+
+/// A property has a name, a value, an a source.  The value is stored as a string, and decoded on
+/// demand.  We don't store the path for the property, since that can be inferred for how the
+/// property was obtained.  There will be lots of redundant strings here, because of lots of common
+/// values.
 #[derive(Debug)]
 pub struct Prop {
     name: String,
     value: String,
-    origin: String,
+    source: Source,
 }
 
 impl Prop {
-    pub fn new(name: &str, value: &str, origin: &str) -> Prop {
+    pub fn new(name: &str, value: &str, source: &str) -> Prop {
         Prop {
             name: name.to_owned(),
             value: value.to_owned(),
-            origin: origin.to_owned(),
+            source: Source::parse(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn props(pairs: &[(&str, &str)]) -> PropSet {
+        let text: String = pairs.iter()
+            .map(|&(name, value)| format!("tank/ds\t{}\t{}\t-\n", name, value))
+            .collect();
+        parse_props(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_values() {
+        let set = props(&[("used", "1048576"), ("quota", "none"), ("compressratio", "1.52x"),
+                          ("compression", "gzip-9"), ("checksum", "sha256"),
+                          ("creation", "1475280000"), ("readonly", "off"), ("atime", "on")]);
+        assert_eq!(set.used(), Some(1048576));
+        assert_eq!(set.get::<u64>("quota"), None);
+        assert_eq!(set.get::<u64>("missing"), None);
+        assert_eq!(set.get::<f64>("compressratio"), Some(1.52));
+        assert_eq!(set.get::<String>("compression"), Some("gzip-9".to_owned()));
+        assert_eq!(set.get::<String>("checksum"), Some("sha256".to_owned()));
+        assert_eq!(set.creation(), Some(1475280000));
+        assert_eq!(set.get::<bool>("readonly"), Some(false));
+        assert_eq!(set.get::<bool>("atime"), Some(true));
+    }
+
+    #[test]
+    fn test_sources() {
+        assert_eq!(Source::parse("local"), Source::Local);
+        assert_eq!(Source::parse("inherited from tank/home"),
+                   Source::Inherited("tank/home".to_owned()));
+        assert_eq!(Source::parse("received"), Source::Received);
+        assert_eq!(Source::parse("-"), Source::None);
+        assert_eq!(Source::Inherited("tank".to_owned()).to_string(), "inherited from tank");
+        assert_eq!(Source::Inherited("tank".to_owned()).kind(), "inherited");
+        assert_eq!(source_kinds("local,received").unwrap(), vec!["local", "received"]);
+        assert!(source_kinds("local,elsewhere").is_err());
+
+        let text = "tank/a\tcompression\tlz4\tlocal\n\
+                    tank/a@s1\tused\t4096\t-\n\
+                    tank/a/b\tcompression\tlz4\tinherited from tank/a\n";
+        let bulk = parse_bulk(text.as_bytes()).unwrap();
+        assert_eq!(bulk.len(), 3);
+        assert_eq!(bulk["tank/a@s1"].used(), Some(4096));
+        assert_eq!(bulk["tank/a/b"].source("compression"),
+                   Some(&Source::Inherited("tank/a".to_owned())));
+        assert!(parse_bulk(b"tank/a\tused\n").is_err());
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value("used", "1048576"), "1.0MiB");
+        assert_eq!(format_value("quota", "none"), "none");
+        assert_eq!(format_value("creation", "1475280000"), "1475280000");
+        assert_eq!(format_value("compression", "lz4"), "lz4");
+    }
+
+    #[test]
+    fn test_access() {
+        let fs = [("type", "filesystem"), ("mounted", "yes"), ("mountpoint", "/tank/ds"),
+                  ("snapdir", "hidden")];
+        assert_eq!(props(&fs).access(), Access::Snapdir("/tank/ds".to_owned()));
+        assert_eq!(props(&[("type", "filesystem"), ("mounted", "no"), ("mountpoint", "none")])
+                   .access(), Access::Mount);
+        assert_eq!(props(&[("type", "filesystem"), ("mounted", "yes"), ("mountpoint", "legacy")])
+                   .access(), Access::Mount);
+        assert_eq!(props(&[("type", "filesystem"), ("mounted", "yes"), ("mountpoint", "/a"),
+                           ("snapdir", "disabled")]).access(), Access::Mount);
+        assert_eq!(props(&[("type", "volume"), ("mounted", "-")]).access(), Access::Volume);
+        match props(&[("type", "filesystem"), ("keystatus", "unavailable")]).access() {
+            Access::Unreachable(why) => assert!(why.contains("key")),
+            other => panic!("Unexpected access: {:?}", other),
         }
     }
 }
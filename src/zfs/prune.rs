@@ -0,0 +1,276 @@
+//! Pruning of old snapshots
+//!
+//! Each dataset's snapshots are judged by its retention policy (see `retain`).  Those judged to
+//! be destroyed are either destroyed, or reported as part of a plan.
+
+use report::Entry;
+use rustc_serialize::json;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::process::Command;
+use std::time::Instant;
+use super::retain::{Candidate, Policy, Verdict};
+use super::relay::humanize;
+use super::{carry_on, check_stop, local_path, DataSet, Result, ZFS};
+
+// The most snapshots to destroy with a single zfs command, unless the config gives another limit.
+const DESTROY_BATCH: usize = 100;
+
+impl<'a> ZFS<'a> {
+    /// The retention policy for the given dataset.
+    fn retention(&self, ds: &DataSet) -> Result<Policy> {
+        let sub = ds.name[self.base().len()..].trim_left_matches('/');
+        match self.root.retention_for(sub) {
+            None => Ok(Policy::default()),
+            Some(ret) => Policy::from_config(ret),
+        }
+    }
+
+    /// Decide the fate of every snapshot of each of the datasets.  Snapshots whose names don't
+    /// match our snapshot pattern are always kept.
+    fn plan_prunes<'s>(&self, snaps: &'s [DataSet]) -> Result<Vec<(Policy, Vec<Verdict<'s>>)>> {
+        let mut policies = vec![];
+        for ds in snaps {
+            policies.push(self.retention(ds)?);
+        }
+        // The creation times are only queried for snapshots whose names don't give them.
+        let mut unknown = vec![];
+        for (ds, policy) in snaps.iter().zip(&policies) {
+            if policy.needs_creation() {
+                for snap in &ds.snaps {
+                    if self.naming.parse(snap).map_or(false, |n| n.time.is_none()) {
+                        unknown.push(format!("{}@{}", ds.name, snap));
+                    }
+                }
+            }
+        }
+        let creation = self.get_props_bulk(&*local_path(self.base()), &unknown, &["creation"])?;
+
+        let mut result = vec![];
+        for (ds, policy) in snaps.iter().zip(policies) {
+            let mut candidates = vec![];
+            for snap in &ds.snaps {
+                match self.naming.parse(snap) {
+                    None => (),
+                    Some(parsed) => {
+                        let full = format!("{}@{}", ds.name, snap);
+                        candidates.push(Candidate {
+                            name: snap,
+                            num: parsed.num,
+                            creation: parsed.time
+                                .or_else(|| creation.get(&full).and_then(|p| p.creation())),
+                        });
+                    },
+                }
+            }
+
+            // Merge the verdicts back in with the snapshots that weren't candidates.
+            let mut judged = policy.plan(&candidates).into_iter().peekable();
+            let mut verdicts = vec![];
+            for snap in &ds.snaps {
+                let matches = judged.peek().map_or(false, |v| v.name == snap);
+                if matches {
+                    verdicts.push(judged.next().unwrap());
+                } else {
+                    verdicts.push(Verdict {
+                        name: snap,
+                        destroy: false,
+                        reason: "not an rback snapshot".to_owned(),
+                    });
+                }
+            }
+
+            result.push((policy, verdicts));
+        }
+        Ok(result)
+    }
+
+    pub fn prune_snaps(&self) -> Result<()> {
+        let snaps = self.get_snaps(local_path(&self.base()))?;
+        let plans = self.plan_prunes(&snaps)?;
+
+        let batch = self.prune_batch()?;
+        for (ds, &(_, ref verdicts)) in snaps.iter().zip(&plans) {
+            println!("name: {}", ds.name);
+            let victims: Vec<_> = verdicts.iter().filter(|v| v.destroy).map(|v| v.name).collect();
+            for chunk in victims.chunks(batch) {
+                check_stop()?;
+                let start = Instant::now();
+                let result = self.destroy_snaps(ds, chunk);
+                self.back.report.record(start, Entry::new(&ds.name, "destroy").snapshots(chunk),
+                                        &result);
+                if let Err(e) = result {
+                    // The rest of the dataset is left for the next prune.
+                    carry_on(self.back, &ds.name, e)?;
+                    break;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// The most snapshots to destroy with a single zfs command.
+    fn prune_batch(&self) -> Result<usize> {
+        let batch = self.root.prune_batch.unwrap_or(DESTROY_BATCH);
+        if batch == 0 {
+            return Err("prune_batch must be at least 1".into());
+        }
+        Ok(batch)
+    }
+
+    /// Destroy a batch of snapshots of a single dataset with one zfs command, which zfs handles
+    /// in a single transaction.
+    fn destroy_snaps(&self, ds: &DataSet, snaps: &[&str]) -> Result<()> {
+        let mut cmd = Command::new("zfs");
+        cmd.arg("destroy");
+        cmd.arg(format!("{}@{}", ds.name, snaps.join(",")));
+        println!(" % {:?}", cmd);
+        if self.back.dry_run {
+            return Ok(());
+        }
+
+        let out = cmd.output()?;
+        if out.status.success() {
+            return Ok(());
+        }
+
+        // zfs reports each snapshot it couldn't destroy as "cannot destroy snapshot
+        // pool/ds@snap: reason".  Find the first of ours it complains about.
+        let err = String::from_utf8_lossy(&out.stderr);
+        for snap in snaps {
+            let full = format!("{}@{}:", ds.name, snap);
+            if let Some(line) = err.lines().find(|l| l.contains(&full)) {
+                return Err(format!("Unable to destroy {}@{} (in batch of {}): {}",
+                                   ds.name, snap, snaps.len(), line.trim()).into());
+            }
+        }
+        Err(format!("Unable to destroy batch of {} snapshots of {}: {:?}: {}",
+                    snaps.len(), ds.name, out.status, err.trim()).into())
+    }
+
+    /// Show what `prune_snaps` would do, and why, without destroying anything.  The space the
+    /// destroys would free is queried from zfs.
+    pub fn prune_plan(&self, as_json: bool) -> Result<()> {
+        let snaps = self.get_snaps(local_path(&self.base()))?;
+        let plans = self.plan_prunes(&snaps)?;
+        let batch = self.prune_batch()?;
+
+        // The space used by each snapshot, which is what destroying it alone would free.
+        let mut names = vec![];
+        for (ds, &(_, ref verdicts)) in snaps.iter().zip(&plans) {
+            names.extend(verdicts.iter()
+                         .filter(|v| v.destroy)
+                         .map(|v| format!("{}@{}", ds.name, v.name)));
+        }
+        let used = self.get_props_bulk(&*local_path(self.base()), &names, &["used"])?;
+
+        let mut reports = vec![];
+        for (ds, &(ref policy, ref verdicts)) in snaps.iter().zip(&plans) {
+            let victims: Vec<_> = verdicts.iter().filter(|v| v.destroy).map(|v| v.name).collect();
+            let mut snapshots = vec![];
+            for v in verdicts {
+                let used = if v.destroy {
+                    let full = format!("{}@{}", ds.name, v.name);
+                    match used.get(&full).and_then(|p| p.used()) {
+                        Some(size) => Some(size),
+                        None => return Err(format!("zfs gave no size for {}", full).into()),
+                    }
+                } else {
+                    None
+                };
+                snapshots.push(SnapPlan {
+                    name: v.name.to_owned(),
+                    action: if v.destroy { "destroy" } else { "keep" }.to_owned(),
+                    reason: v.reason.clone(),
+                    used: used,
+                });
+            }
+            let mut reclaim = 0;
+            for chunk in victims.chunks(batch) {
+                reclaim += self.destroy_estimate(ds, chunk)?;
+            }
+
+            reports.push(DataSetPlan {
+                dataset: ds.name.clone(),
+                policy: policy.to_string(),
+                destroy: victims.len(),
+                batches: (victims.len() + batch - 1) / batch,
+                reclaim: reclaim,
+                snapshots: snapshots,
+            });
+        }
+
+        if as_json {
+            println!("{}", json::as_pretty_json(&reports));
+        } else {
+            for report in &reports {
+                report.show();
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask zfs how much space destroying the given snapshots of a dataset, all together, would
+    /// free.
+    fn destroy_estimate(&self, ds: &DataSet, snaps: &[&str]) -> Result<u64> {
+        let mut cmd = Command::new("zfs");
+        cmd.args(&["destroy", "-nvp", &format!("{}@{}", ds.name, snaps.join(","))]);
+        let out = cmd.output()?;
+        if !out.status.success() {
+            return Err(format!("zfs destroy -n returned error: {:?}", out.status).into());
+        }
+
+        for line in BufReader::new(&out.stdout[..]).lines() {
+            let line = line?;
+            let fields: Vec<_> = line.splitn(2, '\t').collect();
+            if fields.len() == 2 && fields[0] == "reclaim" {
+                return match fields[1].parse::<u64>() {
+                    Ok(size) => Ok(size),
+                    Err(_) => Err(format!("Invalid reclaim size: {:?}", line).into()),
+                };
+            }
+        }
+        Err(format!("zfs destroy -n didn't report reclaim size").into())
+    }
+}
+
+/// The plan for a single dataset, as reported by `prune --plan`.
+#[derive(RustcEncodable)]
+struct DataSetPlan {
+    dataset: String,
+    policy: String,
+    /// How many snapshots would be destroyed.
+    destroy: usize,
+    /// How many zfs commands would destroy them, with `prune_batch` snapshots in each.
+    batches: usize,
+    /// Space freed by destroying all of them, as the sum of what zfs says each batch would free
+    /// on its own.  With more than one batch, this can be low, since destroying snapshots that
+    /// fall in different batches can free space that neither batch alone would.
+    reclaim: u64,
+    snapshots: Vec<SnapPlan>,
+}
+
+#[derive(RustcEncodable)]
+struct SnapPlan {
+    name: String,
+    action: String,
+    reason: String,
+    /// The space used by this snapshot, for those to be destroyed.  This is only what destroying
+    /// it alone would free, and destroying it along with its neighbours may free more.
+    used: Option<u64>,
+}
+
+impl DataSetPlan {
+    fn show(&self) {
+        println!("{} ({})", self.dataset, self.policy);
+        let width = self.snapshots.iter().map(|s| s.name.len()).max().unwrap_or(0);
+        for snap in &self.snapshots {
+            let used = snap.used.map_or_else(String::new, |u| format!("used {}", humanize(u)));
+            println!("  {:<width$}  {:<7}  {:>14}  {}", snap.name, snap.action, used, snap.reason,
+                     width = width);
+        }
+        let at_least = if self.batches > 1 { "at least " } else { "" };
+        println!("  {} to destroy, freeing {}{}", self.destroy, at_least, humanize(self.reclaim));
+    }
+}
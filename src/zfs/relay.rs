@@ -0,0 +1,160 @@
+//! Copy a send stream between processes, showing progress.
+//!
+//! This replaces running 'pv' between 'zfs send' and 'zfs recv'.  The stream passes through our
+//! own process, which lets us show a progress line on a terminal, or periodic log lines when
+//! there is no terminal (such as when run from cron).
+
+use libc;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::time::{Duration, Instant};
+
+/// Tracks the progress of a single stream being copied.
+pub struct Relay {
+    label: String,
+    estimate: u64,
+    bytes: u64,
+    tty: bool,
+    start: Instant,
+    last_shown: Instant,
+    interval: Duration,
+}
+
+impl Relay {
+    /// Construct a new relay for a stream described by `label`, that is expected to be about
+    /// `estimate` bytes long.
+    pub fn new(label: &str, estimate: u64) -> Relay {
+        let tty = unsafe { libc::isatty(libc::STDERR_FILENO) == 1 };
+        let now = Instant::now();
+        Relay {
+            label: label.to_owned(),
+            estimate: estimate,
+            bytes: 0,
+            tty: tty,
+            start: now,
+            last_shown: now,
+            // Log lines are much less frequent than terminal updates, so that they don't flood
+            // the logs.
+            interval: if tty { Duration::from_millis(500) } else { Duration::from_secs(60) },
+        }
+    }
+
+    /// Copy all of `src` into `dest`, updating the progress as we go.  Returns the number of bytes
+    /// copied.
+    pub fn copy<R: Read, W: Write>(&mut self, src: &mut R, dest: &mut W) -> io::Result<u64> {
+        let mut buf = vec![0u8; 256 * 1024];
+        loop {
+            let count = match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            dest.write_all(&buf[..count])?;
+            self.bytes += count as u64;
+
+            if self.last_shown.elapsed() >= self.interval {
+                self.show();
+            }
+        }
+        dest.flush()?;
+        self.finish();
+        Ok(self.bytes)
+    }
+
+    fn show(&mut self) {
+        self.last_shown = Instant::now();
+        let line = self.status();
+        if self.tty {
+            // Pad, to erase anything left over from a longer previous line.
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r{:<78}", line);
+            let _ = stderr.flush();
+        } else {
+            println!("  {}", line);
+        }
+    }
+
+    fn finish(&mut self) {
+        let secs = seconds(self.start.elapsed());
+        let line = format!("{}: {} in {} ({}/s)", self.label, humanize(self.bytes),
+                           format_duration(secs as u64), humanize(rate(self.bytes, secs)));
+        if self.tty {
+            let mut stderr = io::stderr();
+            let _ = writeln!(stderr, "\r{:<78}", line);
+        } else {
+            println!("  {}", line);
+        }
+    }
+
+    /// Format a one line status of the transfer.
+    fn status(&self) -> String {
+        let secs = seconds(self.start.elapsed());
+        let speed = rate(self.bytes, secs);
+        let mut line = format!("{}: {}", self.label, humanize(self.bytes));
+        if self.estimate > 0 {
+            let percent = self.bytes as f64 * 100.0 / self.estimate as f64;
+            line.push_str(&format!(" / {} {:3.0}%", humanize(self.estimate), percent));
+        }
+        line.push_str(&format!(" {}/s", humanize(speed)));
+        if speed > 0 && self.estimate > self.bytes {
+            let eta = (self.estimate - self.bytes) / speed;
+            line.push_str(&format!(" ETA {}", format_duration(eta)));
+        }
+        line
+    }
+}
+
+fn seconds(dur: Duration) -> f64 {
+    dur.as_secs() as f64 + dur.subsec_nanos() as f64 / 1.0e9
+}
+
+fn rate(bytes: u64, secs: f64) -> u64 {
+    if secs > 0.0 {
+        (bytes as f64 / secs) as u64
+    } else {
+        0
+    }
+}
+
+/// Format a size in bytes with a binary unit suffix.
+pub fn humanize(size: u64) -> String {
+    const UNITS: &'static [&'static str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}{}", size, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Format a number of seconds as h:mm:ss.
+fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_duration, humanize};
+
+    #[test]
+    fn test_humanize() {
+        assert_eq!(humanize(0), "0B");
+        assert_eq!(humanize(1023), "1023B");
+        assert_eq!(humanize(1024), "1.0KiB");
+        assert_eq!(humanize(3 * 1024 * 1024 + 512 * 1024), "3.5MiB");
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(format_duration(0), "0:00:00");
+        assert_eq!(format_duration(3725), "1:02:05");
+    }
+}
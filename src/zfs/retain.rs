@@ -0,0 +1,317 @@
+//! Snapshot retention policies.
+//!
+//! A policy decides which of a dataset's snapshots should be pruned.  The original "popcount"
+//! scheme works only from the snapshot numbers.  The "calendar" scheme uses the creation time of
+//! each snapshot to keep the newest snapshot of each recent hour, day, week, month and year.
+
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use config::Retention;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use super::Result;
+
+// For pruning, always keep at least this many of the pruned snapshots.
+pub const PRUNE_KEEP: usize = 10;
+
+/// A snapshot that is a candidate for pruning.
+#[derive(Debug)]
+pub struct Candidate<'a> {
+    pub name: &'a str,
+    /// The number decoded from the snapshot name.
+    pub num: u32,
+    /// The creation time, in seconds since the epoch, if it is known.
+    pub creation: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum Policy {
+    Popcount { keep: usize },
+    Calendar(Calendar),
+}
+
+/// How many of the newest snapshots, one per period, to keep for each kind of period.
+#[derive(Debug, Default)]
+pub struct Calendar {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Period {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::Popcount { keep: PRUNE_KEEP }
+    }
+}
+
+impl Policy {
+    /// Build the policy described by the config file settings.
+    pub fn from_config(ret: &Retention) -> Result<Policy> {
+        match ret.policy.as_ref().map(|x| &x[..]) {
+            None | Some("popcount") => Ok(Policy::Popcount { keep: ret.keep.unwrap_or(PRUNE_KEEP) }),
+            Some("calendar") => {
+                let cal = Calendar {
+                    hourly: ret.hourly.unwrap_or(0),
+                    daily: ret.daily.unwrap_or(0),
+                    weekly: ret.weekly.unwrap_or(0),
+                    monthly: ret.monthly.unwrap_or(0),
+                    yearly: ret.yearly.unwrap_or(0),
+                };
+                if cal.rules().iter().all(|&(_, count)| count == 0) {
+                    return Err("Calendar retention policy doesn't keep any snapshots".into());
+                }
+                Ok(Policy::Calendar(cal))
+            }
+            Some(other) => Err(format!("Unknown retention policy: {:?}", other).into()),
+        }
+    }
+
+    /// Does this policy need the creation time of the snapshots?
+    pub fn needs_creation(&self) -> bool {
+        match *self {
+            Policy::Popcount { .. } => false,
+            Policy::Calendar(_) => true,
+        }
+    }
+
+    /// Decide the fate of each of the candidates, which are given oldest first.  The verdicts are
+    /// returned in the same order.
+    pub fn plan<'a>(&self, snaps: &[Candidate<'a>]) -> Vec<Verdict<'a>> {
+        match *self {
+            Policy::Popcount { keep } => popcount_plan(snaps, keep),
+            Policy::Calendar(ref cal) => cal.plan(&Local, snaps),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Policy::Popcount { keep } => write!(f, "popcount, keep {}", keep),
+            Policy::Calendar(ref cal) => {
+                write!(f, "calendar")?;
+                for &(period, count) in cal.rules().iter().filter(|r| r.1 > 0) {
+                    write!(f, ", {} {}", period.name(), count)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The decision about a single snapshot, and why it was made.
+#[derive(Debug)]
+pub struct Verdict<'a> {
+    pub name: &'a str,
+    pub destroy: bool,
+    pub reason: String,
+}
+
+impl<'a> Verdict<'a> {
+    fn keep(name: &'a str, reason: String) -> Verdict<'a> {
+        Verdict {
+            name: name,
+            destroy: false,
+            reason: reason,
+        }
+    }
+}
+
+/// Prune away earlier snapshots whose number has the same number of bits set as a later one, but
+/// always keep the last `keep` of these.
+fn popcount_plan<'a>(snaps: &[Candidate<'a>], keep: usize) -> Vec<Verdict<'a>> {
+    let mut verdicts: Vec<_> = snaps.iter().map(|s| {
+        Verdict::keep(s.name, format!("newest with popcount {}", s.num.count_ones()))
+    }).collect();
+
+    // Each prune is the index of the snapshot, and the number that collided with it.
+    let mut seen = HashMap::new();
+    let mut prunes = Vec::new();
+    for (index, snap) in snaps.iter().enumerate() {
+        let num = snap.num;
+        seen.insert(num, index);
+
+        // Prune away entries with the same number of bits.
+        let mypop = num.count_ones();
+        for i in 1 .. num {
+            if i.count_ones() != mypop {
+                continue
+            }
+            match seen.entry(i) {
+                Entry::Occupied(ent) => {
+                    prunes.push((ent.remove(), num));
+                },
+                Entry::Vacant(_) => (),
+            }
+        }
+    }
+
+    // Prune the old ones, but make sure to keep some.
+    let floor = prunes.len().saturating_sub(keep);
+    for (i, &(index, by)) in prunes.iter().enumerate() {
+        let verdict = &mut verdicts[index];
+        if i < floor {
+            verdict.destroy = true;
+            verdict.reason = format!("popcount collision with #{}", by);
+        } else {
+            verdict.reason = format!("within PRUNE_KEEP floor (collision with #{})", by);
+        }
+    }
+
+    verdicts
+}
+
+impl Calendar {
+    fn rules(&self) -> [(Period, usize); 5] {
+        [(Period::Hour, self.hourly),
+         (Period::Day, self.daily),
+         (Period::Week, self.weekly),
+         (Period::Month, self.monthly),
+         (Period::Year, self.yearly)]
+    }
+
+    /// Decide which snapshots to keep, using periods in the given time zone.  The newest snapshot,
+    /// and any without a known creation time, are always kept.
+    fn plan<'a, Tz: TimeZone>(&self, tz: &Tz, snaps: &[Candidate<'a>]) -> Vec<Verdict<'a>> {
+        let mut reasons = vec![vec![]; snaps.len()];
+        if let Some(last) = reasons.last_mut() {
+            last.push("newest snapshot".to_owned());
+        }
+
+        for &(period, count) in self.rules().iter() {
+            let mut kept = 0;
+            let mut last_bucket = None;
+            for (i, snap) in snaps.iter().enumerate().rev() {
+                if kept >= count {
+                    break;
+                }
+                let creation = match snap.creation {
+                    None => continue,
+                    Some(creation) => creation,
+                };
+                let b = bucket(tz, period, creation);
+                if last_bucket.as_ref() != Some(&b) {
+                    reasons[i].push(format!("{} bucket {}", period.name(), b));
+                    kept += 1;
+                    last_bucket = Some(b);
+                }
+            }
+        }
+
+        snaps.iter().zip(reasons).map(|(snap, reasons)| {
+            if snap.creation.is_none() {
+                Verdict::keep(snap.name, "creation time unknown".to_owned())
+            } else if reasons.is_empty() {
+                Verdict {
+                    name: snap.name,
+                    destroy: true,
+                    reason: "not the newest of any kept period".to_owned(),
+                }
+            } else {
+                Verdict::keep(snap.name, reasons.join(", "))
+            }
+        }).collect()
+    }
+}
+
+impl Period {
+    fn name(&self) -> &'static str {
+        match *self {
+            Period::Hour => "hourly",
+            Period::Day => "daily",
+            Period::Week => "weekly",
+            Period::Month => "monthly",
+            Period::Year => "yearly",
+        }
+    }
+}
+
+/// Return a name for the period that the given time falls in.
+fn bucket<Tz: TimeZone>(tz: &Tz, period: Period, time: i64) -> String {
+    let t = tz.timestamp(time, 0);
+    match period {
+        Period::Hour => format!("{:04}-{:02}-{:02} {:02}h", t.year(), t.month(), t.day(), t.hour()),
+        Period::Day => format!("{:04}-{:02}-{:02}", t.year(), t.month(), t.day()),
+        Period::Week => {
+            let (year, week, _) = t.isoweekdate();
+            format!("{:04}-W{:02}", year, week)
+        }
+        Period::Month => format!("{:04}-{:02}", t.year(), t.month()),
+        Period::Year => format!("{:04}", t.year()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::UTC;
+    use super::*;
+
+    fn candidates<'a>(names: &'a [String], times: &[i64]) -> Vec<Candidate<'a>> {
+        names.iter().zip(times).enumerate().map(|(i, (n, &t))| {
+            Candidate {
+                name: n,
+                num: i as u32 + 1,
+                creation: Some(t),
+            }
+        }).collect()
+    }
+
+    fn destroyed<'a>(verdicts: Vec<Verdict<'a>>) -> Vec<&'a str> {
+        verdicts.into_iter().filter(|v| v.destroy).map(|v| v.name).collect()
+    }
+
+    #[test]
+    fn test_popcount() {
+        let names: Vec<_> = (1..65).map(|i| format!("s{}", i)).collect();
+        let snaps = candidates(&names, &vec![0; names.len()]);
+
+        // With no floor, every number that has a later one with the same popcount goes.
+        let verdicts = popcount_plan(&snaps, 0);
+        assert_eq!(verdicts[0].reason, "popcount collision with #2");
+        let pruned = destroyed(verdicts);
+        assert!(pruned.contains(&"s1"));
+        assert!(!pruned.contains(&"s64"));
+        assert!(!pruned.contains(&"s63"));
+
+        // The floor keeps the most recently pruned ones.
+        let verdicts = popcount_plan(&snaps, PRUNE_KEEP);
+        let floored = verdicts.iter().filter(|v| v.reason.starts_with("within")).count();
+        assert_eq!(floored, PRUNE_KEEP);
+        assert_eq!(destroyed(verdicts).len(), pruned.len() - PRUNE_KEEP);
+    }
+
+    #[test]
+    fn test_calendar() {
+        // Snapshots every 6 hours for 10 days, starting 2026-10-01 00:00 UTC.
+        let start = 1790812800;
+        let times: Vec<_> = (0..40).map(|i| start + i * 6 * 3600).collect();
+        let names: Vec<_> = (0..40).map(|i| format!("s{}", i)).collect();
+        let snaps = candidates(&names, &times);
+
+        let cal = Calendar {
+            daily: 3,
+            ..Calendar::default()
+        };
+        let verdicts = cal.plan(&UTC, &snaps);
+        assert_eq!(verdicts[35].reason, "daily bucket 2026-10-09");
+        let pruned = destroyed(verdicts);
+
+        // The newest snapshot of each of the last 3 days survives.
+        assert_eq!(pruned.len(), 37);
+        assert!(!pruned.contains(&"s39"));
+        assert!(!pruned.contains(&"s35"));
+        assert!(!pruned.contains(&"s31"));
+        assert!(pruned.contains(&"s38"));
+    }
+}
@@ -0,0 +1,238 @@
+//! An overview of the health of the backups.
+//!
+//! For each dataset, this shows how many snapshots there are and how old the newest is, how many
+//! of the snapshots have sure data (in the `sure` directory, and in the bksure store), and how far
+//! behind each of the replication targets is.  Targets are only shown for the datasets they
+//! replicate, after their `include` and `exclude` settings.
+
+use chrono::UTC;
+use rsure::bk::BkDir;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use super::relay::humanize;
+use super::{local_path, CloneOptions, DataSet, Result, Want, ZFS, ZfsPath};
+
+/// The status of a single dataset, as shown by `rback status`.
+#[derive(Debug, RustcEncodable)]
+pub struct DataSetStatus {
+    pub dataset: String,
+    pub snapshots: usize,
+    pub newest: Option<String>,
+    /// When the newest snapshot was taken, in seconds since the epoch.
+    pub created: Option<i64>,
+    /// The age of the newest snapshot, in seconds.
+    pub age: Option<i64>,
+    /// The space used by the snapshots, in bytes.
+    pub snap_used: Option<u64>,
+    /// The snapshots with sure data in the `sure` directory.
+    pub sure: usize,
+    pub sure_missing: Vec<String>,
+    /// The snapshots with sure data in the bksure store, if there is a store.
+    pub bksure: Option<usize>,
+    pub bksure_missing: Vec<String>,
+    /// The targets that replicate this dataset.
+    pub replicas: Vec<ReplicaStatus>,
+}
+
+/// How a dataset stands on a replication target.
+#[derive(Debug, RustcEncodable)]
+pub struct ReplicaStatus {
+    pub target: String,
+    /// How many snapshots are newer than the newest one the target has.  None if the dataset
+    /// isn't on the target at all.
+    pub behind: Option<usize>,
+    /// Set if the target couldn't be queried.
+    pub error: Option<String>,
+}
+
+impl<'a> ZFS<'a> {
+    /// Gather the status of each of the datasets under the base.  The replication targets are
+    /// only queried if `replicas` is set.
+    pub fn status(&self, replicas: bool) -> Result<Vec<DataSetStatus>> {
+        let now = UTC::now().timestamp();
+        let base = self.base();
+        let snaps = self.get_nonsure_snaps(base)?;
+
+        // The space the snapshots of each dataset use, and when the newest was taken.
+        let mut names = vec![];
+        for ds in &snaps {
+            names.push(ds.name.clone());
+            if let Some(newest) = ds.snaps.last() {
+                names.push(format!("{}@{}", ds.name, newest));
+            }
+        }
+        let props = self.get_props_bulk(&*local_path(base), &names,
+                                        &["creation", "usedbysnapshots"])?;
+
+        let bkd = format!("/{}/bksure", base);
+        let bkpresent = if Path::new(&bkd).is_dir() {
+            Some(BkDir::new(&bkd)?.query()?)
+        } else {
+            None
+        };
+
+        // The snapshots each target has, by dataset name relative to the base.
+        let targets = if replicas { self.back.host.all_targets() } else { &[] };
+        let mut replicas = vec![];
+        for target in targets {
+            let dest = self.root.dest_under(&target.dest);
+            let dest = ZfsPath::parse_ssh(&dest, target.ssh_options.as_ref()
+                                          .map_or(&[], |x| &x[..]));
+            let listing = self.get_snaps(dest.clone()).map(|sets| {
+                sets.into_iter()
+                    .filter(|ds| ds.name.starts_with(dest.name()))
+                    .map(|ds| (ds.name[dest.name().len()..].to_owned(), ds.snaps))
+                    .collect::<HashMap<_, _>>()
+            });
+            replicas.push((target.name(), CloneOptions::from_target(target), listing));
+        }
+
+        let mut result = vec![];
+        for ds in &snaps {
+            let sub = &ds.name[base.len()..];
+            let subname = sub.trim_left_matches('/');
+
+            let newest = ds.snaps.last();
+            let created = newest.and_then(|n| props.get(&format!("{}@{}", ds.name, n)))
+                .and_then(|p| p.creation());
+
+            let sure_missing: Vec<_> = ds.snaps.iter()
+                .filter(|snap| {
+                    !Path::new(&format!("/{}/sure/{}-{}.dat.gz", base, subname, snap)).is_file()
+                })
+                .cloned()
+                .collect();
+
+            let datname = format!("{}.dat", subname);
+            let (bksure, bksure_missing) = match bkpresent {
+                None => (None, vec![]),
+                Some(ref present) => {
+                    let exists = present.iter()
+                        .filter(|x| x.file == datname)
+                        .map(|x| &x.name[..])
+                        .collect::<HashSet<_>>();
+                    let missing: Vec<_> = ds.snaps.iter()
+                        .filter(|snap| !exists.contains(&snap[..]))
+                        .cloned()
+                        .collect();
+                    (Some(ds.snaps.len() - missing.len()), missing)
+                }
+            };
+
+            let replica_status = replicas.iter()
+                .filter(|&&(_, ref opts, _)| opts.wanted(subname) == Want::All)
+                .map(|&(name, _, ref listing)| {
+                    match *listing {
+                        Ok(ref dsets) => ReplicaStatus {
+                            target: name.to_owned(),
+                            behind: dsets.get(sub).map(|dsnaps| behind(ds, dsnaps)),
+                            error: None,
+                        },
+                        Err(ref e) => ReplicaStatus {
+                            target: name.to_owned(),
+                            behind: None,
+                            error: Some(e.to_string()),
+                        },
+                    }
+                }).collect();
+
+            result.push(DataSetStatus {
+                dataset: ds.name.clone(),
+                snapshots: ds.snaps.len(),
+                newest: newest.cloned(),
+                created: created,
+                age: created.map(|t| now - t),
+                snap_used: props.get(&ds.name).and_then(|p| p.used_by_snapshots()),
+                sure: ds.snaps.len() - sure_missing.len(),
+                sure_missing: sure_missing,
+                bksure: bksure,
+                bksure_missing: bksure_missing,
+                replicas: replica_status,
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// The number of snapshots of `ds` that are newer than the newest one in `dsnaps`.
+fn behind(ds: &DataSet, dsnaps: &[String]) -> usize {
+    let present = dsnaps.iter().collect::<HashSet<_>>();
+    match ds.snaps.iter().rposition(|s| present.contains(s)) {
+        Some(pos) => ds.snaps.len() - pos - 1,
+        None => ds.snaps.len(),
+    }
+}
+
+/// Show the status of the datasets as a table.
+pub fn show_status(status: &[DataSetStatus]) {
+    let width = status.iter().map(|s| s.dataset.len()).max().unwrap_or(0);
+    println!("{:<width$}  {:>5}  {:>6}  {:>9}  {:>9}  {:>9}  {}", "dataset", "snaps", "age",
+             "snap-used", "sure", "bksure", "replicas", width = width);
+    for st in status {
+        let age = st.age.map_or_else(|| "-".to_owned(), format_age);
+        let used = st.snap_used.map_or_else(|| "-".to_owned(), humanize);
+        let sure = format!("{}/{}", st.sure, st.snapshots);
+        let bksure = st.bksure.map_or_else(|| "-".to_owned(),
+                                           |n| format!("{}/{}", n, st.snapshots));
+        let replicas: Vec<_> = st.replicas.iter().map(|r| {
+            match (r.behind, &r.error) {
+                (_, &Some(_)) => format!("{}: error", r.target),
+                (None, _) => format!("{}: missing", r.target),
+                (Some(n), _) => format!("{}: {} behind", r.target, n),
+            }
+        }).collect();
+        println!("{:<width$}  {:>5}  {:>6}  {:>9}  {:>9}  {:>9}  {}", st.dataset, st.snapshots,
+                 age, used, sure, bksure, replicas.join(", "), width = width);
+    }
+
+    // The errors are the same for every dataset, so show each only once.
+    let mut shown = HashSet::new();
+    for r in status.iter().flat_map(|st| &st.replicas) {
+        if let Some(ref e) = r.error {
+            if shown.insert(&r.target) {
+                println!("Error querying {}: {}", r.target, e);
+            }
+        }
+    }
+}
+
+/// Format an age in seconds briefly, with the largest unit that fits.
+fn format_age(secs: i64) -> String {
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 60 * 60 {
+        format!("{}m", secs / 60)
+    } else if secs < 2 * 24 * 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else {
+        format!("{}d", secs / (24 * 60 * 60))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{behind, format_age};
+    use zfs::{local_path, DataSet};
+
+    #[test]
+    fn test_behind() {
+        let ds = DataSet {
+            dir: local_path("tank/home"),
+            name: "tank/home".to_owned(),
+            snaps: vec!["a-1".to_owned(), "a-2".to_owned(), "a-3".to_owned()],
+            mount: "/home".to_owned(),
+        };
+        assert_eq!(behind(&ds, &["a-3".to_owned()]), 0);
+        assert_eq!(behind(&ds, &["a-1".to_owned(), "a-2".to_owned()]), 1);
+        assert_eq!(behind(&ds, &["other".to_owned()]), 3);
+        assert_eq!(behind(&ds, &[]), 3);
+    }
+
+    #[test]
+    fn test_age() {
+        assert_eq!(format_age(30), "30s");
+        assert_eq!(format_age(3600 + 120), "1h");
+        assert_eq!(format_age(47 * 3600), "47h");
+        assert_eq!(format_age(3 * 86400), "3d");
+    }
+}
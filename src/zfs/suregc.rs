@@ -0,0 +1,276 @@
+//! Removal of the sure data of snapshots that are gone.
+//!
+//! `run_sure` writes a surefile for each snapshot, named `<sub>-<snap>.dat.gz`, under the `sure`
+//! directory of the base, and `run_bksure` checks one into `<sub>.dat` in the bksure store.
+//! Nothing removed these when prune destroyed the snapshots, so `sure_gc` does.
+//!
+//! When none of the remaining snapshots of a dataset have a surefile, the one of the newest
+//! destroyed snapshot is kept, as `run_sure` uses it as the base for hashing the next snapshot
+//! incrementally.  The bksure store keeps every revision in its history, so the revisions of
+//! destroyed snapshots can't be taken out of it, but the files of datasets that are gone are
+//! removed.
+
+use report::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+use super::relay::humanize;
+use super::{carry_on, DataSet, Result, ZFS};
+
+/// A surefile in the sure directory.
+#[derive(Debug)]
+pub struct SureName {
+    pub path: PathBuf,
+    /// The dataset, relative to the base.
+    subname: String,
+    snap: String,
+    num: u32,
+}
+
+impl<'a> ZFS<'a> {
+    /// Find the surefiles in the sure directory.  `subnames` are the names of the datasets
+    /// (relative to the base), which help to split the names of the files.  Files that aren't
+    /// named for one of our snapshots are left out.
+    pub fn sure_names(&self, subnames: &[&str]) -> Result<Vec<SureName>> {
+        let dir = PathBuf::from(format!("/{}/sure", self.base()));
+        let mut files = vec![];
+        if dir.is_dir() {
+            find_files(&dir, &mut files)?;
+        }
+
+        let mut result = vec![];
+        for path in files {
+            let split = match path.strip_prefix(&dir).ok().and_then(|p| p.to_str()) {
+                Some(rel) if rel.ends_with(".dat.gz") => {
+                    self.split_sure_name(&rel[..rel.len() - 7], subnames)
+                        .map(|(sub, snap, num)| (sub.to_owned(), snap.to_owned(), num))
+                }
+                _ => None,
+            };
+            if let Some((subname, snap, num)) = split {
+                result.push(SureName {
+                    path: path,
+                    subname: subname,
+                    snap: snap,
+                    num: num,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Split the name of a surefile (without the ".dat.gz") into the dataset and the snapshot.
+    /// Both may contain dashes, so the known datasets are tried first, longest first, and then
+    /// each dash in turn, until the rest is a name of one of our snapshots.
+    fn split_sure_name<'n>(&self, name: &'n str, subnames: &[&str])
+                           -> Option<(&'n str, &'n str, u32)> {
+        let mut known: Vec<_> = subnames.iter()
+            .filter(|s| name.starts_with(&format!("{}-", s)))
+            .map(|s| s.len())
+            .collect();
+        known.sort_by(|a, b| b.cmp(a));
+        let dashes = name.char_indices().filter(|&(_, c)| c == '-').map(|(i, _)| i);
+
+        for pos in known.into_iter().chain(dashes) {
+            let snap = &name[pos + 1..];
+            if let Some(parsed) = self.naming.parse(snap) {
+                return Some((&name[..pos], snap, parsed.num));
+            }
+        }
+        None
+    }
+
+    /// Remove the sure data of snapshots that no longer exist.
+    pub fn sure_gc(&self) -> Result<()> {
+        let base = self.base();
+        let snaps = self.get_nonsure_snaps(base)?;
+        let subnames: Vec<_> = snaps.iter().map(|ds| &ds.name[base.len() + 1..]).collect();
+        let names = self.sure_names(&subnames)?;
+
+        let mut by_dataset = BTreeMap::new();
+        for name in &names {
+            by_dataset.entry(&name.subname[..]).or_insert_with(Vec::new).push(name);
+        }
+
+        let mut count = 0;
+        let mut total = 0;
+        for (subname, files) in by_dataset {
+            let ds = snaps.iter().find(|ds| &ds.name[base.len() + 1..] == subname);
+            let kept = ds.and_then(|ds| kept_base(&names, subname, ds));
+            let stale: Vec<_> = files.into_iter()
+                .filter(|n| !ds.map_or(false, |ds| ds.snaps.contains(&n.snap)))
+                .filter(|n| kept.map_or(true, |k| k.path != n.path))
+                .collect();
+            if let Some(k) = kept {
+                println!("Keep {} as the base for the next sure", k.path.display());
+            }
+            if stale.is_empty() {
+                continue;
+            }
+
+            let start = Instant::now();
+            let result = self.remove_sure_files(&stale);
+            let removed: Vec<_> = stale.iter().map(|n| &n.snap[..]).collect();
+            let entry = Entry::new(&format!("{}/{}", base, subname), "sure-gc")
+                .snapshots(&removed);
+            self.back.report.record_bytes(start, entry, &result);
+            match result {
+                Ok(bytes) => {
+                    total += bytes;
+                    count += stale.len();
+                }
+                Err(e) => carry_on(self.back, &format!("{}/{}", base, subname), e)?,
+            }
+        }
+
+        self.bksure_gc(&subnames)?;
+        println!("Removed the sure data of {} snapshots, reclaiming {}", count, humanize(total));
+        Ok(())
+    }
+
+    /// Remove surefiles, returning the space they took.
+    fn remove_sure_files(&self, files: &[&SureName]) -> Result<u64> {
+        let mut total = 0;
+        for file in files {
+            total += fs::metadata(&file.path)?.len();
+            println!("  % rm {}", file.path.display());
+            if !self.back.dry_run {
+                fs::remove_file(&file.path)?;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Remove the files of datasets that are gone from the bksure store.
+    fn bksure_gc(&self, subnames: &[&str]) -> Result<()> {
+        let dir = format!("/{}/bksure", self.base());
+        if !Path::new(&dir).is_dir() {
+            return Ok(());
+        }
+
+        let out = Command::new("bk").arg("gfiles").current_dir(&dir).output()?;
+        if !out.status.success() {
+            return Err(format!("bk gfiles returned error: {:?}", out.status).into());
+        }
+        let mut gone = vec![];
+        for line in BufReader::new(&out.stdout[..]).lines() {
+            let line = line?;
+            if line.ends_with(".dat") && !subnames.contains(&&line[..line.len() - 4]) {
+                gone.push(line);
+            }
+        }
+        if gone.is_empty() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let result = bk(&dir, &["rm"], &gone, self.back.dry_run).and_then(|_| {
+            bk(&dir, &["commit", "-yrback sure-gc"], &[], self.back.dry_run)
+        });
+        for file in &gone {
+            let entry = Entry::new(&format!("{}/{}", self.base(), &file[..file.len() - 4]),
+                                   "sure-gc");
+            self.back.report.record(start, entry, &result);
+        }
+        result
+    }
+}
+
+/// The surefile of a destroyed snapshot of the dataset that `run_sure` should use as the base
+/// for hashing incrementally, when none of the remaining snapshots have one.  This is the one of
+/// the newest destroyed snapshot.
+pub fn kept_base<'n>(names: &'n [SureName], subname: &str, ds: &DataSet)
+                     -> Option<&'n SureName> {
+    let files = names.iter().filter(|n| n.subname == subname);
+    if files.clone().any(|n| ds.snaps.contains(&n.snap)) {
+        return None;
+    }
+    files.max_by_key(|n| n.num)
+}
+
+/// Add all of the files under `dir` to `files`.
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            find_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Run a bk command in the store.
+fn bk(dir: &str, args: &[&str], files: &[String], dry_run: bool) -> Result<()> {
+    let mut cmd = Command::new("bk");
+    cmd.args(args);
+    cmd.args(files);
+    cmd.current_dir(dir);
+    if dry_run {
+        println!("Would run: {:?}", cmd);
+        return Ok(());
+    }
+    println!("Run: {:?}", cmd);
+    let stat = cmd.status()?;
+    if !stat.success() {
+        return Err(format!("Unable to run bk command: {:?}", stat).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Host;
+    use report::Report;
+    use std::path::PathBuf;
+    use zfs::{local_path, DataSet, ZFS};
+    use RBack;
+
+    #[test]
+    fn test_sure_names() {
+        let back = RBack {
+            host: Host::for_base("tank/home", "h-"),
+            dry_run: true,
+            fail_fast: false,
+            report: Report::new(),
+        };
+        let roots = back.host.roots().unwrap();
+        let zfs = ZFS::new(&back, &roots[0]).unwrap();
+
+        let subs = ["db", "db-old", "db/logs"];
+        assert_eq!(zfs.split_sure_name("db-h-00002-10-01", &subs),
+                   Some(("db", "h-00002-10-01", 2)));
+        assert_eq!(zfs.split_sure_name("db-old-h-00003-10-01", &subs),
+                   Some(("db-old", "h-00003-10-01", 3)));
+        assert_eq!(zfs.split_sure_name("db/logs-h-00004-10-01", &subs),
+                   Some(("db/logs", "h-00004-10-01", 4)));
+        assert_eq!(zfs.split_sure_name("gone-h-00005-10-01", &subs),
+                   Some(("gone", "h-00005-10-01", 5)));
+        assert_eq!(zfs.split_sure_name("db-notes", &subs), None);
+
+        let name = |sub: &str, snap: &str, num| SureName {
+            path: PathBuf::from(format!("{}-{}.dat.gz", sub, snap)),
+            subname: sub.to_owned(),
+            snap: snap.to_owned(),
+            num: num,
+        };
+        let names = vec![name("db", "h-00001-10-01", 1), name("db", "h-00003-10-01", 3),
+                         name("db", "h-00002-10-01", 2)];
+        let mut ds = DataSet {
+            dir: local_path("tank/home/db"),
+            name: "tank/home/db".to_owned(),
+            snaps: vec!["h-00004-10-02".to_owned()],
+            mount: "/home/db".to_owned(),
+        };
+        assert_eq!(kept_base(&names, "db", &ds).map(|n| n.num), Some(3));
+        assert!(kept_base(&names, "other", &ds).is_none());
+        ds.snaps.insert(0, "h-00002-10-01".to_owned());
+        assert!(kept_base(&names, "db", &ds).is_none());
+    }
+}
@@ -0,0 +1,463 @@
+//! Verify the sure data of consecutive snapshots against each other.
+//!
+//! Between two snapshots, files are expected to come and go, and to change.  But a file whose
+//! contents changed, while its size and modification time stayed the same, was almost certainly
+//! not changed in the usual way, so such files are reported as suspect.
+//!
+//! This can only find what the sure data recorded, and that isn't bit rot.  `sure` and `bksure`
+//! build the data of each snapshot after the first by updating it from the one before, which
+//! keeps the old hash of any file whose inode and ctime are unchanged, without reading it again.
+//! Rot doesn't change the ctime, so a rotted file keeps the hash it had when it was good.  What
+//! is found is a file rewritten with its modification time put back, as tampering might do.  Rot
+//! is for zfs itself to find, with `zpool scrub`.
+
+use config::Target;
+use rsure::{CompareAction, CompareType, CompareVisitor, SureTree, TreeCompare};
+use rsure::bk::{BkDir, BkSureFile};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Instant;
+use report::Entry;
+use super::{check_stop, DataSet, Result, ZFS, ZfsPath};
+
+/// The changes between the sure data of two snapshots of a dataset.
+#[derive(Debug, RustcEncodable)]
+pub struct SnapChanges {
+    pub dataset: String,
+    pub old: String,
+    pub new: String,
+    pub added: usize,
+    pub deleted: usize,
+    pub modified: usize,
+    /// Files whose contents changed, although their size and modification time did not.
+    pub suspect: Vec<String>,
+}
+
+impl SnapChanges {
+    fn new(dataset: &str, old: &str, new: &str) -> SnapChanges {
+        SnapChanges {
+            dataset: dataset.to_owned(),
+            old: old.to_owned(),
+            new: new.to_owned(),
+            added: 0,
+            deleted: 0,
+            modified: 0,
+            suspect: vec![],
+        }
+    }
+}
+
+impl CompareVisitor for SnapChanges {
+    fn visit(&mut self, name: &Path, _kind: CompareType, action: CompareAction,
+             atts: Option<&[String]>) {
+        match action {
+            CompareAction::Add => self.added += 1,
+            CompareAction::Delete => self.deleted += 1,
+            CompareAction::Modify => {
+                self.modified += 1;
+                if atts.map_or(false, is_suspect) {
+                    self.suspect.push(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+}
+
+/// Given the attributes that differ for a file, is the change suspect?  Only a change that was
+/// hashed again shows a new sha1, so this isn't a check for bit rot (see above).
+fn is_suspect(atts: &[String]) -> bool {
+    atts.iter().any(|a| a == "sha1") && !atts.iter().any(|a| a == "size" || a == "mtime")
+}
+
+/// The sure data of the snapshots under a base, both in the `sure` directory, and in the bksure
+/// store, if there is one.
+struct SureData<'b> {
+    base: &'b str,
+    bk: Option<(BkDir, Vec<BkSureFile>)>,
+}
+
+impl<'b> SureData<'b> {
+    fn open(base: &'b str) -> Result<SureData<'b>> {
+        let bkd = format!("/{}/bksure", base);
+        let bk = if Path::new(&bkd).is_dir() {
+            let bkd = BkDir::new(&bkd)?;
+            let present = bkd.query()?;
+            Some((bkd, present))
+        } else {
+            None
+        };
+        Ok(SureData {
+            base: base,
+            bk: bk,
+        })
+    }
+
+    /// Load the sure data of a snapshot of the dataset `subname` (relative to the base), from
+    /// the `sure` directory, or failing that, from the bksure store.  Returns None if there is
+    /// no sure data for the snapshot.
+    fn load(&self, subname: &str, snap: &str) -> Result<Option<SureTree>> {
+        let name = format!("/{}/sure/{}-{}.dat.gz", self.base, subname, snap);
+        if Path::new(&name).is_file() {
+            return Ok(Some(SureTree::load(&name)?));
+        }
+        if let Some((ref bkd, ref present)) = self.bk {
+            let datname = format!("{}.dat", subname);
+            if present.iter().any(|x| x.file == datname && x.name == snap) {
+                return Ok(Some(bkd.load(&datname, snap)?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> ZFS<'a> {
+    /// Compare the sure data of each snapshot with that of the one before it, for each of the
+    /// datasets under the base.  The sure data is taken from the `sure` directory, or failing
+    /// that, from the bksure store.  Snapshots with neither are skipped over.
+    pub fn verify(&self) -> Result<Vec<SnapChanges>> {
+        let base = self.base();
+        let snaps = self.get_nonsure_snaps(base)?;
+        let data = SureData::open(base)?;
+
+        let mut result = vec![];
+        for ds in &snaps {
+            println!("Verify {:?}", ds.name);
+            let subname = ds.name[base.len()..].trim_left_matches('/');
+
+            let mut last: Option<(&str, SureTree)> = None;
+            for snap in &ds.snaps {
+                let tree = match data.load(subname, snap)? {
+                    Some(tree) => tree,
+                    None => continue,
+                };
+                check_stop()?;
+
+                if let Some((old, old_tree)) = last {
+                    let start = Instant::now();
+                    let mut changes = SnapChanges::new(&ds.name, old, snap);
+                    tree.compare_from(&mut changes, &old_tree, Path::new("."));
+
+                    let check = if changes.suspect.is_empty() {
+                        Ok(())
+                    } else {
+                        Err(format!("{} files changed without a change in size or mtime",
+                                    changes.suspect.len()))
+                    };
+                    let entry = Entry::new(&ds.name, "verify").snapshots(&[old, &snap[..]]);
+                    self.back.report.record(start, entry, &check);
+                    result.push(changes);
+                }
+                last = Some((snap, tree));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Check the files of the datasets replicated to `target` against the sure data of the
+    /// source.  Each dataset is checked at `snap`, if given, or otherwise at the newest snapshot
+    /// on the target with sure data.  The snapshot is hashed where it is, so this reads every
+    /// file of it on the target's host.
+    pub fn verify_replica(&self, target: &Target, snap: Option<&str>)
+                          -> Result<Vec<ReplicaCheck>> {
+        let base = self.base();
+        let snaps = self.get_nonsure_snaps(base)?;
+        let data = SureData::open(base)?;
+
+        let dest = self.root.dest_under(&target.dest);
+        let dest = ZfsPath::parse_ssh(&dest, target.ssh_options.as_ref().map_or(&[], |x| &x[..]));
+        let dsets = self.get_snaps(dest.clone())?;
+        let dmap: HashMap<_, _> = dsets.iter()
+            .filter(|ds| ds.name.starts_with(dest.name()))
+            .map(|ds| (&ds.name[dest.name().len()..], ds))
+            .collect();
+
+        let mut result = vec![];
+        for ds in &snaps {
+            let sub = &ds.name[base.len()..];
+            let subname = sub.trim_left_matches('/');
+            let dset = match dmap.get(sub) {
+                Some(dset) => dset,
+                None => {
+                    println!("Skip: {} is not on {}", ds.name, target.name());
+                    continue;
+                }
+            };
+
+            // The snapshots present on both sides, newest first.
+            let common: Vec<_> = ds.snaps.iter().rev()
+                .filter(|s| dset.snaps.contains(s) && snap.map_or(true, |n| n == &s[..]))
+                .collect();
+            let mut found = None;
+            for name in common {
+                if let Some(tree) = data.load(subname, name)? {
+                    found = Some((name, tree));
+                    break;
+                }
+            }
+            let (name, tree) = match found {
+                Some(found) => found,
+                None => {
+                    println!("Skip: {} has no snapshot with sure data on {}", ds.name,
+                             target.name());
+                    continue;
+                }
+            };
+            check_stop()?;
+
+            println!("Verify {}@{} on {}", dset.name, name, target.name());
+            let start = Instant::now();
+            let mut check = ReplicaCheck::new(&ds.name, &dset.name, name);
+            let scan = self.replica_sums(dset, name).map(|sums| {
+                check.compare(&tree_sums(&tree), &sums);
+            });
+            let scan = scan.and_then(|_| {
+                let bad = check.missing.len() + check.extra.len() + check.differ.len();
+                if bad == 0 {
+                    Ok(())
+                } else {
+                    Err(format!("{} files don't match the source", bad).into())
+                }
+            });
+            let entry = Entry::new(&ds.name, "verify").snapshots(&[name]).dest(&dset.name);
+            self.back.report.record(start, entry, &scan);
+            if let Err(e) = scan {
+                check.error = Some(e.to_string());
+            }
+            result.push(check);
+        }
+        Ok(result)
+    }
+
+    /// Hash the regular files of a snapshot of a dataset on a replica, returning the sha1 of
+    /// each, indexed by its escaped path.  Changing into the snapshot directory is enough to
+    /// get it automounted.
+    fn replica_sums(&self, dset: &DataSet, snap: &str) -> Result<BTreeMap<String, String>> {
+        if !dset.mount.starts_with('/') {
+            return Err(format!("{} is not mounted ({:?})", dset.name, dset.mount).into());
+        }
+        let dir = format!("{}/.zfs/snapshot/{}", dset.mount, snap);
+        let script = format!("cd {} && find . -type f -exec sha1sum {{}} +", shell_quote(&dir));
+        let out = dset.dir.shell(&script).output()?;
+        if !out.status.success() {
+            return Err(format!("Unable to hash files in {:?}: {:?}", dir, out.status).into());
+        }
+        parse_sums(&out.stdout)
+    }
+}
+
+/// How the files of a snapshot on a replica compare with the sure data of the source.
+#[derive(Debug, RustcEncodable)]
+pub struct ReplicaCheck {
+    pub dataset: String,
+    pub dest: String,
+    pub snapshot: String,
+    /// The number of files checked.
+    pub files: usize,
+    /// Files that the source has, but the replica doesn't.
+    pub missing: Vec<String>,
+    /// Files that the replica has, but the source doesn't.
+    pub extra: Vec<String>,
+    /// Files with different contents.
+    pub differ: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl ReplicaCheck {
+    fn new(dataset: &str, dest: &str, snapshot: &str) -> ReplicaCheck {
+        ReplicaCheck {
+            dataset: dataset.to_owned(),
+            dest: dest.to_owned(),
+            snapshot: snapshot.to_owned(),
+            files: 0,
+            missing: vec![],
+            extra: vec![],
+            differ: vec![],
+            error: None,
+        }
+    }
+
+    /// Compare the hashes of the source with those of the replica.
+    fn compare(&mut self, src: &BTreeMap<String, String>, dest: &BTreeMap<String, String>) {
+        self.files = src.len();
+        for (name, sum) in src {
+            match dest.get(name) {
+                None => self.missing.push(name.clone()),
+                Some(dsum) if dsum != sum => self.differ.push(name.clone()),
+                Some(_) => (),
+            }
+        }
+        self.extra = dest.keys().filter(|n| !src.contains_key(*n)).cloned().collect();
+    }
+}
+
+/// The sha1 of each regular file in a sure tree, indexed by its escaped path.
+fn tree_sums(tree: &SureTree) -> BTreeMap<String, String> {
+    fn walk(tree: &SureTree, prefix: &str, sums: &mut BTreeMap<String, String>) {
+        for file in &tree.files {
+            if file.atts.get("kind").map_or(false, |k| k == "file") {
+                if let Some(sum) = file.atts.get("sha1") {
+                    sums.insert(format!("{}{}", prefix, file.name), sum.clone());
+                }
+            }
+        }
+        for child in &tree.children {
+            walk(child, &format!("{}{}/", prefix, child.name), sums);
+        }
+    }
+
+    let mut sums = BTreeMap::new();
+    walk(tree, "", &mut sums);
+    sums
+}
+
+/// Parse the output of `sha1sum` run on paths beginning with "./".  Names containing a newline
+/// or backslash have them escaped by sha1sum, and the line marked with a leading backslash.  The
+/// names are escaped the way rsure escapes them, so that they can be compared.
+fn parse_sums(buf: &[u8]) -> Result<BTreeMap<String, String>> {
+    let mut sums = BTreeMap::new();
+    for line in buf.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+        let (line, escaped) = match line.split_first() {
+            Some((&b'\\', rest)) => (rest, true),
+            _ => (line, false),
+        };
+        let bad = || format!("Unexpected sha1sum output: {:?}", String::from_utf8_lossy(line));
+        if line.len() < 44 || &line[40..44] != b"  ./" {
+            return Err(bad().into());
+        }
+        let sum = String::from_utf8(line[..40].to_vec())?;
+
+        let mut name = vec![];
+        let mut chars = line[44..].iter();
+        while let Some(&ch) = chars.next() {
+            if escaped && ch == b'\\' {
+                match chars.next() {
+                    Some(&b'n') => name.push(b'\n'),
+                    Some(&b'\\') => name.push(b'\\'),
+                    _ => return Err(bad().into()),
+                }
+            } else {
+                name.push(ch);
+            }
+        }
+        sums.insert(escape(&name), sum);
+    }
+    Ok(sums)
+}
+
+/// Escape a name the way rsure does, with every byte other than the printable ASCII characters
+/// (and '=') written as "=xx".
+fn escape(name: &[u8]) -> String {
+    let mut result = String::new();
+    for &ch in name {
+        if b'!' <= ch && ch <= b'~' && ch != b'=' {
+            result.push(ch as char);
+        } else {
+            result.push_str(&format!("={:02x}", ch));
+        }
+    }
+    result
+}
+
+/// Quote text so that the shell takes it literally.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Show the changes between snapshots, and any suspect files.
+pub fn show_verify(changes: &[SnapChanges]) {
+    for ch in changes {
+        println!("{}@{} -> {}: {} added, {} deleted, {} modified{}", ch.dataset, ch.old, ch.new,
+                 ch.added, ch.deleted, ch.modified,
+                 if ch.suspect.is_empty() { "" } else { ", SUSPECT" });
+        for name in &ch.suspect {
+            println!("    suspect: {}", name);
+        }
+    }
+
+    let suspect: usize = changes.iter().map(|ch| ch.suspect.len()).sum();
+    if suspect > 0 {
+        println!("{} files changed contents without a change in size or mtime", suspect);
+    }
+}
+
+/// Show how the replicas compare, listing any files that don't match.
+pub fn show_replica_checks(checks: &[ReplicaCheck]) {
+    for ch in checks {
+        match ch.error {
+            None => println!("{}@{}: {} files match", ch.dest, ch.snapshot, ch.files),
+            Some(ref e) => println!("{}@{}: {}", ch.dest, ch.snapshot, e),
+        }
+        for name in &ch.missing {
+            println!("    missing: {}", name);
+        }
+        for name in &ch.extra {
+            println!("    extra: {}", name);
+        }
+        for name in &ch.differ {
+            println!("    differs: {}", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rsure::{SureTree, TreeCompare};
+    use std::iter;
+    use std::path::Path;
+
+    // Build a tree of files, given as (name, size, mtime, sha1).
+    fn tree(files: &[(&str, u64, u64, &str)]) -> SureTree {
+        let mut text = "asure-2.0\n-----\nd__root__ [kind dir ]\n-\n".to_owned();
+        for &(name, size, mtime, sha1) in files {
+            text.push_str(&format!("f{} [kind file mtime {} sha1 {} size {} ]\n",
+                                   name, mtime, sha1, size));
+        }
+        text.push_str("u\n");
+        SureTree::load_from(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let old = tree(&[("gone", 10, 100, "ee"),
+                         ("rotted", 10, 100, "dd"),
+                         ("same", 10, 100, "aa"),
+                         ("touched", 10, 100, "cc"),
+                         ("written", 10, 100, "bb")]);
+        let new = tree(&[("new", 10, 100, "ff"),
+                         ("rotted", 10, 100, "d2"),
+                         ("same", 10, 100, "aa"),
+                         ("touched", 10, 200, "cc"),
+                         ("written", 12, 200, "b2")]);
+
+        let mut changes = SnapChanges::new("tank/home", "a-1", "a-2");
+        new.compare_from(&mut changes, &old, Path::new("."));
+        assert_eq!(changes.added, 1);
+        assert_eq!(changes.deleted, 1);
+        assert_eq!(changes.modified, 3);
+        assert_eq!(changes.suspect, vec!["./rotted".to_owned()]);
+    }
+
+    #[test]
+    fn test_replica() {
+        let sum = |c| iter::repeat(c).take(40).collect::<String>();
+        let (aa, bb, cc) = (sum('a'), sum('b'), sum('c'));
+        let out = format!("{}  ./same\n{}  ./odd name\n\\{}  ./new\\nline\n{}  ./extra\n",
+                          aa, bb, cc, aa);
+        let dest = parse_sums(out.as_bytes()).unwrap();
+        assert_eq!(dest.keys().collect::<Vec<_>>(), vec!["extra", "new=0aline", "odd=20name",
+                                                         "same"]);
+        assert!(parse_sums(b"garbage\n").is_err());
+
+        let src = tree(&[("gone", 10, 100, &aa), ("new=0aline", 10, 100, &aa),
+                         ("odd=20name", 10, 100, &bb), ("same", 10, 100, &aa)]);
+        let mut check = ReplicaCheck::new("tank/home", "backup/home", "a-1");
+        check.compare(&tree_sums(&src), &dest);
+        assert_eq!(check.files, 4);
+        assert_eq!(check.missing, vec!["gone".to_owned()]);
+        assert_eq!(check.extra, vec!["extra".to_owned()]);
+        assert_eq!(check.differ, vec!["new=0aline".to_owned()]);
+
+        assert_eq!(shell_quote("/a b/it's"), "'/a b/it'\\''s'");
+    }
+}